@@ -0,0 +1,77 @@
+//! Tunes the weights of the board evaluator on a set of problems and
+//! writes them to a file usable with `play_icfp2015 -w`.
+
+extern crate play_icfp2015;
+extern crate getopts;
+
+use play_icfp2015::{formats, strategy, encoder, evaluator, tuner};
+use play_icfp2015::error::{Error, Result};
+use play_icfp2015::options::parse;
+use getopts::Options;
+use std::io::{self, Write};
+use std::env;
+use std::process;
+
+fn run() -> Result<()> {
+    let args: Vec<String> = env::args().collect();
+    let mut opts = Options::new();
+    opts.optmulti("f", "", "Problem to tune on, may be repeated", "FILENAME");
+    opts.optopt("n", "", "Seeds used from every problem (default 1)", "NUMBER");
+    opts.optmulti("p", "", "Phrase of power", "STRING");
+    opts.optopt("w", "", "Weights to start from", "FILENAME");
+    opts.optopt("o", "", "Where to write the best weights (default weights.json)",
+                "FILENAME");
+    opts.optopt("g", "", "Number of generations (default 20)", "NUMBER");
+    opts.optopt("l", "", "Weight vectors per generation (default 8)", "NUMBER");
+    opts.optopt("k", "", "Units the solver looks ahead, counting the current one (default 1)",
+                "NUMBER");
+    opts.optopt("b", "", "Beam width of the solver (default 16)", "NUMBER");
+    opts.optopt("r", "", "Seed of the random number generator (default 42)", "NUMBER");
+    opts.optopt("c", "", "Number of processor cores available", "NUMBER");
+    let matches = try!(opts.parse(&args[1..]).map_err(|f| Error::Usage(f.to_string())));
+    let number = |name: &str, default: usize| {
+        parse(&matches, name).map(|n| n.unwrap_or(default))
+    };
+
+    let n_seeds = try!(number("n", 1));
+    let mut games = Vec::new();
+    let phrases = try!(encoder::normalize_phrases(&matches.opt_strs("p")));
+    for path in matches.opt_strs("f") {
+        let board: formats::Board = try!(formats::load(&path));
+        let seeds: Vec<u64> = board.sourceSeeds.iter().cloned().take(n_seeds).collect();
+        games.extend(try!(board.games_for_seeds(&seeds, &phrases)));
+    }
+    if games.is_empty() {
+        return Err(Error::Usage("no problems to tune on, pass some with -f".to_string()))
+    }
+
+    let config = tuner::Config {
+        phrases: phrases,
+        search: strategy::Search {
+            depth: try!(number("k", 1)),
+            width: try!(number("b", strategy::Search::default().width))
+        },
+        generations: try!(number("g", 20)),
+        population: try!(number("l", 8)),
+        rng_seed: try!(number("r", 42)) as u32,
+        cores: try!(number("c", 1))
+    };
+    let start = match matches.opt_str("w") {
+        Some(path) => try!(evaluator::Weights::load(&path)),
+        None => evaluator::Weights::default()
+    };
+    let output = matches.opt_str("o").unwrap_or("weights.json".to_string());
+    try!(tuner::tune(&games, &start, &config, |generation, weights, score| {
+        writeln!(&mut io::stderr(), "generation {}: best score {:.1}",
+                 generation, score).unwrap();
+        weights.save(&output)
+    }));
+    Ok(())
+}
+
+fn main() {
+    if let Err(e) = run() {
+        writeln!(&mut io::stderr(), "tune: {}", e).unwrap();
+        process::exit(e.exit_code());
+    }
+}
@@ -1,51 +1,141 @@
-use std::rc::Rc;
+use std::sync::Arc;
 use hex2d::{Coordinate, ToCoordinate};
-use game::Unit;
+use rustc_serialize::{Encodable, Encoder};
+use game::{Shape, Unit};
+use error::{self, Error};
 
-#[derive(RustcEncodable, Clone)]
+/// Cells of a unit that lie in one row: bit `i` of `bits` stands for the
+/// cell `(x + i, y)`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct RowMask {
+    pub x: i32,
+    pub y: i32,
+    pub bits: u64
+}
+
+/// Rows are packed into `words` 64-bit words each, bit `x % 64` of word
+/// `x / 64` standing for the cell in column `x`.
+#[derive(Clone)]
 pub struct Board {
     pub width: usize,
     pub height: usize,
-    cells: Rc<Vec<Vec<bool>>>
+    words: usize,
+    cells: Arc<Vec<u64>>
+}
+
+impl Encodable for Board {
+    fn encode<S: Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
+        let cells: Vec<Vec<bool>> = (0..self.height as i32).map(|y| {
+            (0..self.width as i32).map(|x| !self.is_free(x, y)).collect()
+        }).collect();
+        s.emit_struct("Board", 3, |s| {
+            try!(s.emit_struct_field("width", 0, |s| self.width.encode(s)));
+            try!(s.emit_struct_field("height", 1, |s| self.height.encode(s)));
+            s.emit_struct_field("cells", 2, |s| cells.encode(s))
+        })
+    }
 }
 
 impl Board {
-    pub fn new<I>(width: usize, height: usize, filled: I) -> Board
+    pub fn new<I>(width: usize, height: usize, filled: I) -> error::Result<Board>
         where I: Iterator<Item=(i32, i32)>
     {
-        let mut cells = vec![vec![false; width]; height];
+        let words = (width + 63) / 64;
+        let mut cells = vec![0; words * height];
         for (x, y) in filled {
-            assert!(0 <= x && x < width as i32);
-            assert!(0 <= y && y < height as i32);
-            cells[y as usize][x as usize] = true;
+            if !(0 <= x && x < width as i32 && 0 <= y && y < height as i32) {
+                return Err(Error::Geometry(format!(
+                    "filled cell ({}, {}) is off the {}x{} board", x, y, width, height)))
+            }
+            cells[y as usize * words + x as usize / 64] |= 1 << (x % 64);
         }
-        Board {
+        Ok(Board {
             width: width,
             height: height,
-            cells: Rc::new(cells)
+            words: words,
+            cells: Arc::new(cells)
+        })
+    }
+
+    /// Bytes taken by the cells.
+    pub fn bytes(&self) -> usize {
+        self.cells.len() * ::std::mem::size_of::<u64>()
+    }
+
+    fn rows<'a>(&'a self) -> ::std::slice::Chunks<'a, u64> {
+        self.cells.chunks(self.words)
+    }
+
+    /// Value of the last word of a full row; the others are all ones.
+    fn last_word_full(&self) -> u64 {
+        match self.width % 64 {
+            0 => !0,
+            n => (1 << n) - 1
         }
     }
 
+    fn is_row_full(&self, row: &[u64]) -> bool {
+        let (last, init) = row.split_last().unwrap();
+        *last == self.last_word_full() && init.iter().all(|&w| w == !0)
+    }
+
     pub fn n_clear_top_rows(&self) -> usize {
-        self.cells.iter()
-            .take_while(|row| row.iter().all(|c| !c))
+        self.rows()
+            .take_while(|row| row.iter().all(|&w| w == 0))
             .count()
     }
 
     pub fn total_sum(&self, penalty_per_row: &Vec<i64>) -> i64 {
         assert!(penalty_per_row.len() == self.height);
-        self.cells.iter()
+        self.rows()
             .zip(penalty_per_row)
-            .map(|(row, penalty)| (row.iter().filter(|&&x| x).count() as i64) * penalty)
+            .map(|(row, penalty)| {
+                let filled = row.iter().map(|w| w.count_ones()).fold(0, |a, b| a + b);
+                (filled as i64) * penalty
+            })
             .fold(0, |a, b| a + b) // sum is ustable tt
     }
 
     pub fn n_full_rows(&self) -> usize {
-        self.cells.iter()
-            .filter(|&l| Board::check_line_filled(&l))
+        self.rows()
+            .filter(|row| self.is_row_full(row))
             .count()
     }
 
+    /// Height of every column above the floor, counted from its topmost
+    /// filled cell.
+    pub fn column_heights(&self) -> Vec<usize> {
+        (0..self.width as i32).map(|x| {
+            (0..self.height as i32)
+                .find(|&y| !self.is_free(x, y))
+                .map_or(0, |y| self.height - y as usize)
+        }).collect()
+    }
+
+    /// Number of filled/free changes between horizontally adjacent cells,
+    /// the side walls counting as filled.
+    pub fn row_transitions(&self) -> usize {
+        let w = self.width as i32;
+        (0..self.height as i32).map(|y| {
+            let filled = |x: i32| x < 0 || x >= w || !self.is_free(x, y);
+            (0..w + 1).filter(|&x| filled(x - 1) != filled(x)).count()
+        }).fold(0, |a, b| a + b)
+    }
+
+    /// Number of free cells above the top of their column whose left and
+    /// right neighbours are filled or walls.
+    pub fn n_well_cells(&self) -> usize {
+        let w = self.width as i32;
+        let heights = self.column_heights();
+        (0..w).map(|x| {
+            let top = (self.height - heights[x as usize]) as i32;
+            (0..top).filter(|&y| {
+                (x == 0 || !self.is_free(x - 1, y)) &&
+                (x == w - 1 || !self.is_free(x + 1, y))
+            }).count()
+        }).fold(0, |a, b| a + b)
+    }
+
     pub fn n_holes(&self) -> usize {
         return (0..self.width).flat_map(|x| {
             (0..self.height).map(move |y| (x, y))
@@ -71,11 +161,34 @@ impl Board {
     /// Returns `true` if a `unit` is within board boundaries and does
     /// not overlap any of the occupied cells.
     pub fn check_unit_position(&self, unit: &Unit) -> bool {
-        unit.iter().all(|(x, y)| {
-            self.is_valid(x, y) && self.is_free(x, y)
+        self.fits(unit.row_masks(), unit.pivot())
+    }
+
+    /// Returns `true` if all of the `masks`, shifted by `(dx, dy)`, are
+    /// within board boundaries and do not overlap any of the occupied cells.
+    pub fn fits(&self, masks: &[RowMask], (dx, dy): (i32, i32)) -> bool {
+        masks.iter().all(|m| {
+            let (x, y) = (m.x + dx, m.y + dy);
+            let span = 64 - m.bits.leading_zeros() as i32;
+            (0 <= y && y < self.height as i32) &&
+            (0 <= x && x + span <= self.width as i32) &&
+            self.window(x as usize, y as usize) & m.bits == 0
         })
     }
 
+    /// 64 cells of row `y` starting from column `x`, cells past the right
+    /// border read as free.
+    fn window(&self, x: usize, y: usize) -> u64 {
+        let row = &self.cells[y * self.words..(y + 1) * self.words];
+        let (word, offset) = (x / 64, x % 64);
+        let low = row[word] >> offset;
+        if offset > 0 && word + 1 < self.words {
+            low | (row[word + 1] << (64 - offset))
+        } else {
+            low
+        }
+    }
+
     /// Return `true` if the command is locking the unit.
     // pub fn is_command_locking(&self, unit: &Unit, c: &Command) {
     //     unit.apply(c).iter().all(|c| {
@@ -84,25 +197,21 @@ impl Board {
     //     })
     // }
 
-    fn check_line_filled(line: &Vec<bool>) -> bool {
-        line.iter().all(|&c| c)
-    }
-
-    pub fn clear_filled_lines(&self, cells: &Vec<Vec<bool>>) -> (Board, i32) {
-        let mut old_cells : Vec<Vec<bool>> = Vec::new();
-
-        for line in cells.iter() {
-            if !Board::check_line_filled(&line) {
-                old_cells.push(line.clone())
+    fn clear_filled_lines(&self, cells: Vec<u64>) -> (Board, i32) {
+        let mut new_cells = Vec::with_capacity(cells.len());
+        let mut lines_cleared = 0;
+        for row in cells.chunks(self.words) {
+            if self.is_row_full(row) {
+                lines_cleared += 1;
+            } else {
+                new_cells.extend(row.iter().cloned());
             }
         }
-        let lines_cleared = self.height - old_cells.len();
-
-        let mut new_cells = vec![vec![false; self.width]; lines_cleared];
-        new_cells.extend(old_cells);
+        let mut cells = vec![0; lines_cleared * self.words];
+        cells.extend(new_cells);
 
         let board = Board {
-            cells: Rc::new(new_cells),
+            cells: Arc::new(cells),
             ..*self
         };
         (board, lines_cleared as i32)
@@ -110,11 +219,12 @@ impl Board {
 
     pub fn is_free(&self, x: i32, y: i32) -> bool {
         assert!(self.is_valid(x, y));
-        !self.cells[y as usize][x as usize]
+        let word = self.cells[y as usize * self.words + x as usize / 64];
+        word & (1 << (x % 64)) == 0
     }
 
-    pub fn place_new_unit<'a>(&self, cells: &'a Vec<Coordinate>) -> Unit<'a> {
-        let unit = Unit::new(cells);
+    pub fn place_new_unit<'a>(&self, shape: &'a Shape) -> Unit<'a> {
+        let unit = Unit::new(shape);
         let (_, y) = cube_to_offset(&unit.position.to_coordinate());
         let target_y = y - unit.border_top();
         let unit = unit.move_to(offset_to_cube(&(0, target_y)));
@@ -130,9 +240,9 @@ impl Board {
         let mut cells = (*self.cells).clone();
         for (x, y) in unit.iter() {
             assert!(self.is_free(x, y));
-            cells[y as usize][x as usize] = true;
+            cells[y as usize * self.words + x as usize / 64] |= 1 << (x % 64);
         }
-        self.clear_filled_lines(&cells)
+        self.clear_filled_lines(cells)
     }
 
     fn is_valid(&self, x: i32, y: i32) -> bool {
@@ -167,3 +277,21 @@ fn offset_cube_id() {
         assert!(cube_to_offset(&offset_to_cube(&(x, y))) == (x, y));
     }
 }
+
+#[test]
+fn lines_are_cleared() {
+    // 70 columns make rows span two words.
+    let filled = (0..70).map(|x| (x, 2)).chain((0..69).map(|x| (x, 1)));
+    let board = Board::new(70, 3, filled).unwrap();
+    assert!(board.n_full_rows() == 1);
+    assert!(board.n_clear_top_rows() == 1);
+    let (board, cleared) = board.clear_filled_lines((*board.cells).clone());
+    assert!(cleared == 1);
+    assert!(board.n_clear_top_rows() == 2);
+    assert!(!board.is_free(68, 2) && board.is_free(69, 2));
+    assert!(board.is_free(0, 1) && !board.is_free(64, 2));
+    assert!(board.fits(&[RowMask { x: 63, y: 1, bits: 0b11 }], (0, 0)));
+    assert!(!board.fits(&[RowMask { x: 62, y: 2, bits: 0b101 }], (0, 0)));
+    assert!(board.fits(&[RowMask { x: 68, y: 1, bits: 0b1 }], (1, 1)));
+    assert!(!board.fits(&[RowMask { x: 69, y: 2, bits: 0b11 }], (0, 0)));
+}
@@ -1,6 +1,11 @@
+use std::cmp;
+use std::collections::HashSet;
+use std::fmt;
+
+use error::{self, Error};
 use game::{Command};
 use hex2d::{Angle, Direction};
-use std::iter::{repeat};
+use scoring::power_score;
 
 static YX_SYMBOLS: [char; 6] = [
     'p', '\'', '!', '.', '0', '3'
@@ -38,70 +43,246 @@ fn get_symbols(c: &Command) -> [char; 6] {
     }
 }
 
-fn symbol_to_command(sym: char) -> Command {
+/// Returns the symbol used to emit `c` outside of phrases of power.
+pub fn command_to_symbol(c: &Command) -> char {
+    get_symbols(c)[0]
+}
+
+/// Returns the command encoded by `sym`, in either case, or `None` if it
+/// encodes none.
+pub fn symbol_to_command(sym: char) -> Option<Command> {
+    let sym = sym.to_lowercase().next().unwrap_or(sym);
     if YX_SYMBOLS.iter().any(|s| sym == *s) {
-        Command::Move(Direction::YX)
+        Some(Command::Move(Direction::YX))
     }
     else if XY_SYMBOLS.iter().any(|s| sym == *s) {
-        Command::Move(Direction::XY)
+        Some(Command::Move(Direction::XY))
     }
     else if ZX_SYMBOLS.iter().any(|s| sym == *s) {
-        Command::Move(Direction::ZX)
+        Some(Command::Move(Direction::ZX))
     }
     else if ZY_SYMBOLS.iter().any(|s| sym == *s) {
-        Command::Move(Direction::ZY)
+        Some(Command::Move(Direction::ZY))
     }
     else if L_SYMBOLS.iter().any(|s| sym == *s) {
-        Command::Rotate(Angle::Left)
+        Some(Command::Rotate(Angle::Left))
     }
     else if R_SYMBOLS.iter().any(|s| sym == *s) {
-        Command::Rotate(Angle::Right)
+        Some(Command::Rotate(Angle::Right))
     }
     else {
-        panic!("No power!")
+        None
+    }
+}
+
+/// Symbols the spec says to skip in solutions.
+pub fn is_ignored(sym: char) -> bool {
+    sym == '\t' || sym == '\n' || sym == '\r'
+}
+
+/// Why a string does not decode to commands.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum DecodeError {
+    /// A symbol that encodes no command, and its position in the decoded
+    /// string, counted in characters.
+    UnknownSymbol { symbol: char, position: usize },
+    /// A phrase of no commands, which every command would spell.
+    Empty
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DecodeError::UnknownSymbol { symbol, position } =>
+                write!(f, "unknown symbol {:?} at position {}", symbol, position),
+            DecodeError::Empty => write!(f, "no commands")
+        }
+    }
+}
+
+/// Commands encoded by `s`, skipping ignored symbols.
+pub fn decode(s: &str) -> Result<Vec<Command>, DecodeError> {
+    let mut commands = Vec::new();
+    for (i, sym) in s.chars().enumerate() {
+        if is_ignored(sym) {
+            continue
+        }
+        match symbol_to_command(sym) {
+            Some(c) => commands.push(c),
+            None => return Err(DecodeError::UnknownSymbol { symbol: sym, position: i })
+        }
     }
+    Ok(commands)
 }
 
+/// `phrase` the way games and `encode` expect it: lower case, without
+/// ignored symbols. It has to spell at least one command.
+pub fn normalize_phrase(phrase: &str) -> Result<String, DecodeError> {
+    if try!(decode(phrase)).is_empty() {
+        return Err(DecodeError::Empty)
+    }
+    Ok(phrase.chars()
+       .filter(|&c| !is_ignored(c))
+       .flat_map(|c| c.to_lowercase())
+       .collect())
+}
+
+/// `normalize_phrase` of every one of `phrases`.
+pub fn normalize_phrases(phrases: &[String]) -> error::Result<Vec<String>> {
+    phrases.iter()
+        .map(|p| normalize_phrase(p).map_err(|e| Error::Phrase(p.clone(), e)))
+        .collect()
+}
+
+/// Commands of a phrase checked by `normalize_phrase`.
 pub fn phrase_to_commands(phrase: &String) -> Vec<Command> {
-    phrase.chars().map(symbol_to_command).collect()
-}
-
-fn place_phrase(phrase: &String,
-                commands: &Vec<Command>,
-                result: &mut Vec<char>,
-                used: &mut Vec<bool>) {
-    let seq = phrase_to_commands(phrase);
-    for i in 0..commands.len() - seq.len() {
-        let mut flag = false;
-        for j in 0..seq.len() {
-            if used[i + j] || seq[j] != commands[i + j] {
-                flag = true;
-                break;
-            }
+    decode(phrase).unwrap()
+}
+
+/// A phrase placed at `start` of the command list.
+#[derive(Clone, Copy, PartialEq, Debug)]
+struct Placed {
+    start: usize,
+    phrase: usize
+}
+
+/// Best non-overlapping placements of `seqs` in `commands`, by the points
+/// every occurrence earns, given that the `fixed` ones must be kept.
+/// `matches[p]` are the phrases whose commands start at `p`.
+fn best_placements(commands: &Vec<Command>, seqs: &Vec<Vec<Command>>,
+                   matches: &Vec<Vec<usize>>, fixed: &Vec<Placed>)
+                   -> (Vec<Placed>, Vec<i32>, Vec<i32>) {
+    let n = commands.len();
+    let none = i32::min_value() / 2;
+    let mut fixed_at = vec![None; n];
+    let mut covered = vec![false; n + 1];
+    for f in fixed.iter() {
+        fixed_at[f.start] = Some(f.phrase);
+        for p in f.start..f.start + seqs[f.phrase].len() {
+            covered[p] = true;
         }
-        if flag {
-            continue;
+    }
+    let points = |i: usize| 2 * seqs[i].len() as i32;
+    let free = |p: usize, i: usize| !(p..p + seqs[i].len()).any(|q| covered[q]);
+
+    // Best points of the commands before `p`, and from `p` on.
+    let mut before = vec![none; n + 1];
+    before[0] = 0;
+    for p in 0..n {
+        if before[p] == none {
+            continue
         }
-        for j in 0..seq.len() {
-            result[i + j] = phrase.chars().nth(j).unwrap();
-            used[i + j] = true;
+        let options: Vec<usize> = match fixed_at[p] {
+            Some(i) => vec![i],
+            None if covered[p] => vec![],
+            None => matches[p].iter().cloned().filter(|&i| free(p, i)).collect()
+        };
+        if fixed_at[p].is_none() && !covered[p] {
+            before[p + 1] = cmp::max(before[p + 1], before[p]);
         }
+        for i in options {
+            let end = p + seqs[i].len();
+            before[end] = cmp::max(before[end], before[p] + points(i));
+        }
+    }
+    let mut after = vec![none; n + 1];
+    after[n] = 0;
+    for p in (0..n).rev() {
+        after[p] = match fixed_at[p] {
+            Some(i) => after[p + seqs[i].len()] + points(i),
+            None if covered[p] => none,
+            None => matches[p].iter().filter(|&&i| free(p, i))
+                .map(|&i| after[p + seqs[i].len()] + points(i))
+                .fold(after[p + 1], cmp::max)
+        };
     }
+
+    let mut placed = Vec::new();
+    let mut p = 0;
+    while p < n {
+        let next = match fixed_at[p] {
+            Some(i) => Some(i),
+            None => matches[p].iter().cloned().find(|&i| {
+                free(p, i) && after[p + seqs[i].len()] + points(i) == after[p]
+            })
+        };
+        match next {
+            Some(i) => {
+                placed.push(Placed { start: p, phrase: i });
+                p += seqs[i].len();
+            }
+            None => p += 1
+        }
+    }
+    (placed, before, after)
+}
+
+/// `power_score` of the phrases `placed` spell.
+fn placed_score(placed: &Vec<Placed>, seqs: &Vec<Vec<Command>>) -> i32 {
+    (0..seqs.len()).map(|i| {
+        let reps = placed.iter().filter(|p| p.phrase == i).count() as i32;
+        power_score(seqs[i].len() as i32, reps)
+    }).fold(0, |a, b| a + b)
 }
 
+/// Spells `commands` with as many points from `power_phrases` as it can.
+/// The occurrences to spell are chosen by dynamic programming over the
+/// command list, which is exact for the points each occurrence earns; the
+/// bonus of phrases left out is then won by forcing in their best
+/// occurrence for as long as that pays. Every other command gets a symbol
+/// that is in no phrase where there is one, so no phrase is spelled by
+/// accident.
 pub fn encode(commands: &Vec<Command>, power_phrases: &Vec<String>) -> String {
-    let mut sorted_phrases = power_phrases.clone();
-    sorted_phrases.sort_by(|a, b| a.len().cmp(&b.len()));
-    let mut result = repeat('*').take(commands.len()).collect::<Vec<_>>();
-    let mut used = repeat(false).take(commands.len()).collect::<Vec<_>>();
-    for p in &sorted_phrases {
-        place_phrase(p, commands, &mut result, &mut used);
-    };
-    for i in 0..result.len() {
-        if !used[i] {
-            result[i] = get_symbols(&commands[i])[0];
+    let n = commands.len();
+    let seqs: Vec<Vec<Command>> = power_phrases.iter().map(phrase_to_commands).collect();
+    let matches: Vec<Vec<usize>> = (0..n).map(|p| {
+        (0..seqs.len()).filter(|&i| {
+            !seqs[i].is_empty() && p + seqs[i].len() <= n &&
+                seqs[i][..] == commands[p..p + seqs[i].len()]
+        }).collect()
+    }).collect();
+
+    let mut fixed = Vec::new();
+    let (mut placed, mut before, mut after) =
+        best_placements(commands, &seqs, &matches, &fixed);
+    loop {
+        let score = placed_score(&placed, &seqs);
+        let missing = (0..seqs.len()).filter(|&i| placed.iter().all(|p| p.phrase != i));
+        // The occurrence of a missing phrase that costs the fewest points.
+        let forced = missing.flat_map(|i| {
+            let (before, after, seqs, matches) = (&before, &after, &seqs, &matches);
+            (0..n).filter(move |&p| matches[p].contains(&i))
+                .map(move |p| (before[p] + after[p + seqs[i].len()], p, i))
+        }).max();
+        let candidate = match forced {
+            Some((_, p, i)) => {
+                let mut fixed = fixed.clone();
+                fixed.push(Placed { start: p, phrase: i });
+                let result = best_placements(commands, &seqs, &matches, &fixed);
+                (fixed, result)
+            }
+            None => break
+        };
+        if placed_score(&(candidate.1).0, &seqs) <= score {
+            break
         }
-    };
+        fixed = candidate.0;
+        let (p, b, a) = candidate.1;
+        placed = p;
+        before = b;
+        after = a;
+    }
+
+    let in_phrases: HashSet<char> = power_phrases.iter().flat_map(|p| p.chars()).collect();
+    let mut result: Vec<char> = commands.iter().map(|c| {
+        get_symbols(c).iter().cloned().find(|s| !in_phrases.contains(s))
+            .unwrap_or(command_to_symbol(c))
+    }).collect();
+    for p in placed {
+        for (j, sym) in power_phrases[p.phrase].chars().enumerate() {
+            result[p.start + j] = sym;
+        }
+    }
     result.into_iter().collect()
 }
 
@@ -112,3 +293,32 @@ fn encode_it_right() {
                  Command::Move(Direction::ZX),
                  Command::Move(Direction::YX)]);
 }
+
+#[test]
+fn phrases_are_placed_to_the_last_command() {
+    let phrases = vec!["ei!".to_string(), "yuggoth".to_string()];
+    let mut commands = phrase_to_commands(&"ei!".to_string());
+    commands.insert(0, Command::Move(Direction::ZX));
+    assert!(encode(&commands, &phrases) == "aei!");
+    // Longer than the commands.
+    assert!(encode(&commands[..1].to_vec(), &phrases) == "a");
+}
+
+#[test]
+fn every_phrase_gets_its_bonus() {
+    // "alal" twice has the most points per occurrence, but one of them
+    // makes way for "la" and its bonus.
+    let phrases = vec!["alal".to_string(), "la".to_string()];
+    let commands = phrase_to_commands(&"alalalal".to_string());
+    assert!(encode(&commands, &phrases) == "alalglam");
+}
+
+#[test]
+fn decoding_skips_ignored_symbols() {
+    assert!(decode("E\ti!\r\n") == Ok(phrase_to_commands(&"ei!".to_string())));
+    assert!(decode("ei#!") == Err(DecodeError::UnknownSymbol { symbol: '#', position: 2 }));
+    assert!(normalize_phrase("Ia!\n") == Ok("ia!".to_string()));
+    assert!(normalize_phrase("") == Err(DecodeError::Empty));
+    assert!(normalize_phrase("\r\n") == Err(DecodeError::Empty));
+    assert!(normalize_phrases(&["ei!".to_string(), "".to_string()]).is_err());
+}
@@ -0,0 +1,79 @@
+use std::error;
+use std::fmt;
+use std::io;
+use std::result;
+
+use rustc_serialize::json;
+
+use encoder::DecodeError;
+
+/// Everything that can go wrong between reading a problem and printing
+/// its solutions.
+#[derive(Debug)]
+pub enum Error {
+    /// Bad command line options.
+    Usage(String),
+    /// A file that could not be read or written.
+    Io(String, io::Error),
+    /// A file that is not the JSON we expect.
+    Json(String, json::DecoderError),
+    /// Output that could not be encoded as JSON.
+    Encode(json::EncoderError),
+    /// A problem whose board or units make no sense.
+    Geometry(String),
+    /// A phrase of power with a symbol that encodes no command.
+    Phrase(String, DecodeError),
+    /// The search got somewhere it should not have; a bug.
+    Search(String)
+}
+
+pub type Result<T> = result::Result<T, Error>;
+
+impl Error {
+    /// Exit code of the binaries for the error: 2 for usage, 3 for I/O,
+    /// 4 for JSON, 5 for geometry, 6 for phrases and 70 for bugs.
+    pub fn exit_code(&self) -> i32 {
+        match *self {
+            Error::Usage(_) => 2,
+            Error::Io(..) => 3,
+            Error::Json(..) | Error::Encode(_) => 4,
+            Error::Geometry(_) => 5,
+            Error::Phrase(..) => 6,
+            Error::Search(_) => 70
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Usage(ref msg) => write!(f, "{}", msg),
+            Error::Io(ref path, ref e) => write!(f, "{}: {}", path, e),
+            Error::Json(ref path, ref e) => write!(f, "{}: {}", path, e),
+            Error::Encode(ref e) => write!(f, "cannot encode the output: {}", e),
+            Error::Geometry(ref msg) => write!(f, "invalid problem: {}", msg),
+            Error::Phrase(ref phrase, ref e) => write!(f, "phrase {:?}: {}", phrase, e),
+            Error::Search(ref msg) => write!(f, "search failed: {}", msg)
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn description(&self) -> &str {
+        match *self {
+            Error::Usage(_) => "bad command line",
+            Error::Io(..) => "I/O error",
+            Error::Json(..) => "bad JSON",
+            Error::Encode(_) => "cannot encode JSON",
+            Error::Geometry(_) => "invalid problem",
+            Error::Phrase(..) => "undecodable phrase",
+            Error::Search(_) => "search failed"
+        }
+    }
+}
+
+impl From<json::EncoderError> for Error {
+    fn from(e: json::EncoderError) -> Error {
+        Error::Encode(e)
+    }
+}
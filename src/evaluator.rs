@@ -0,0 +1,145 @@
+use std::fs;
+use std::io::Write;
+
+use rustc_serialize::json;
+
+use board::Board;
+use error::{Error, Result};
+use formats;
+
+/// Rates a board right after a unit has been locked on it. Higher is
+/// better.
+pub trait Evaluator: Sync {
+    /// `cleared_lines` is the number of lines the lock cleared.
+    fn evaluate(&self, board: &Board, cleared_lines: i32) -> i64;
+}
+
+/// Weights of the linear evaluator over these board features:
+///
+/// * `holes`: free cells with at most one free neighbour, see
+///   `Board::is_hole`;
+/// * `aggregate_height`: sum of the column heights;
+/// * `bumpiness`: sum of height differences between adjacent columns;
+/// * `row_transitions`: see `Board::row_transitions`;
+/// * `wells`: see `Board::n_well_cells`;
+/// * `cleared_lines`: lines cleared by the lock;
+/// * `cell_height`: filled cells weighted by their distance from the floor.
+///
+/// Loadable from a JSON file such as
+///
+/// ```json
+/// {"holes": -50, "aggregate_height": -5, "bumpiness": -2,
+///  "row_transitions": -3, "wells": -4, "cleared_lines": 10000,
+///  "cell_height": -1}
+/// ```
+#[derive(Debug, Clone, PartialEq, RustcDecodable, RustcEncodable)]
+pub struct Weights {
+    pub holes: f64,
+    pub aggregate_height: f64,
+    pub bumpiness: f64,
+    pub row_transitions: f64,
+    pub wells: f64,
+    pub cleared_lines: f64,
+    pub cell_height: f64
+}
+
+impl Default for Weights {
+    /// The hand-picked weights the solver started with.
+    fn default() -> Weights {
+        Weights {
+            holes: 0.0,
+            aggregate_height: 0.0,
+            bumpiness: 0.0,
+            row_transitions: 0.0,
+            wells: 0.0,
+            cleared_lines: 10000.0,
+            cell_height: -1.0
+        }
+    }
+}
+
+impl Weights {
+    pub fn load(path: &str) -> Result<Weights> {
+        formats::load(path)
+    }
+
+    pub fn save(&self, path: &str) -> Result<()> {
+        fs::File::create(path)
+            .and_then(|mut file| writeln!(&mut file, "{}", json::as_pretty_json(self)))
+            .map_err(|e| Error::Io(path.to_string(), e))
+    }
+
+    /// The weights in declaration order, for the tuner.
+    pub fn to_vec(&self) -> Vec<f64> {
+        vec![self.holes, self.aggregate_height, self.bumpiness,
+             self.row_transitions, self.wells, self.cleared_lines,
+             self.cell_height]
+    }
+
+    pub fn from_slice(w: &[f64]) -> Weights {
+        assert!(w.len() == 7);
+        Weights {
+            holes: w[0],
+            aggregate_height: w[1],
+            bumpiness: w[2],
+            row_transitions: w[3],
+            wells: w[4],
+            cleared_lines: w[5],
+            cell_height: w[6]
+        }
+    }
+}
+
+impl Evaluator for Weights {
+    fn evaluate(&self, board: &Board, cleared_lines: i32) -> i64 {
+        // Features with zero weight are not computed at all.
+        fn term<F>(weight: f64, feature: F) -> f64 where F: FnOnce() -> usize {
+            if weight == 0.0 { 0.0 } else { weight * feature() as f64 }
+        }
+
+        let heights = if self.aggregate_height != 0.0 || self.bumpiness != 0.0 {
+            board.column_heights()
+        } else {
+            Vec::new()
+        };
+        let penalty: Vec<_> = (0..board.height as i64).rev().collect();
+        let score = term(self.holes, || board.n_holes())
+            + term(self.aggregate_height, || heights.iter().fold(0, |a, b| a + b))
+            + term(self.bumpiness, || bumpiness(&heights))
+            + term(self.row_transitions, || board.row_transitions())
+            + term(self.wells, || board.n_well_cells())
+            + self.cleared_lines * cleared_lines as f64
+            + term(self.cell_height, || board.total_sum(&penalty) as usize);
+        score as i64
+    }
+}
+
+fn bumpiness(heights: &Vec<usize>) -> usize {
+    heights.windows(2)
+        .map(|w| if w[0] > w[1] { w[0] - w[1] } else { w[1] - w[0] })
+        .fold(0, |a, b| a + b)
+}
+
+#[test]
+fn features_of_a_small_board() {
+    //  . . . .
+    //   # . # .
+    //  # # # .
+    let board = Board::new(4, 3, vec![(0, 1), (2, 1), (0, 2), (1, 2), (2, 2)].into_iter())
+        .unwrap();
+    let heights = board.column_heights();
+    assert!(heights == vec![2, 1, 2, 0]);
+    assert!(bumpiness(&heights) == 4);
+    // Row 0 has two transitions at the walls, rows 1 and 2 four and two.
+    assert!(board.row_transitions() == 8);
+    // (1, 1), and (3, 1) and (3, 2) against the right wall.
+    assert!(board.n_well_cells() == 3);
+
+    let mut weights = Weights::default();
+    assert!(weights.evaluate(&board, 1) == 10000 - 2);
+    weights.cleared_lines = 0.0;
+    weights.cell_height = 0.0;
+    weights.bumpiness = 1.0;
+    weights.wells = 10.0;
+    assert!(weights.evaluate(&board, 1) == 34);
+}
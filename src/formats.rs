@@ -1,7 +1,20 @@
+use std::fs;
+use std::io::Read;
+
 use hex2d;
+use rustc_serialize::{json, Decodable};
 
 use game;
 use board;
+use error::{Error, Result};
+
+/// Reads and decodes the JSON file at `path`.
+pub fn load<T: Decodable>(path: &str) -> Result<T> {
+    let mut data = String::new();
+    try!(fs::File::open(path).and_then(|mut f| f.read_to_string(&mut data))
+         .map_err(|e| Error::Io(path.to_string(), e)));
+    json::decode(&data).map_err(|e| Error::Json(path.to_string(), e))
+}
 
 #[derive(Debug, RustcDecodable, RustcEncodable)]
 #[allow(non_snake_case)]
@@ -16,42 +29,130 @@ pub struct Board {
 }
 
 impl Board {
-    pub fn games(&self) -> Vec<game::Game> {
-        self.sourceSeeds.iter().map(|&s| self.game_for_seed(s)).collect()
+    /// Everything wrong with the problem, one message per problem found.
+    pub fn check(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        if self.width == 0 || self.height == 0 {
+            problems.push(format!("the board is {}x{}", self.width, self.height));
+        }
+        for c in &self.filled {
+            if !(0 <= c.x && c.x < self.width as i32 && 0 <= c.y && c.y < self.height as i32) {
+                problems.push(format!("filled cell ({}, {}) is off the {}x{} board",
+                                      c.x, c.y, self.width, self.height));
+            }
+        }
+        if self.units.is_empty() {
+            problems.push("there are no units".to_string());
+        }
+        if self.sourceLength == 0 {
+            problems.push("the source has no units".to_string());
+        }
+        if self.sourceSeeds.is_empty() {
+            problems.push("there are no source seeds".to_string());
+        }
+
+        let empty = board::Board::new(self.width, self.height, None.into_iter());
+        let cells: Vec<Vec<hex2d::Coordinate>> = self.units.iter().map(|u| {
+            let mut cells: Vec<_> = u.members.iter()
+                .map(|&c| hex2d::Coordinate::from(c) - hex2d::Coordinate::from(u.pivot))
+                .collect();
+            cells.sort();
+            cells
+        }).collect();
+        for (i, unit) in cells.iter().enumerate() {
+            if unit.is_empty() {
+                problems.push(format!("unit {} has no members", i));
+                continue
+            }
+            if unit.windows(2).any(|w| w[0] == w[1]) {
+                problems.push(format!("unit {} has a member twice", i));
+            }
+            if let Ok(ref board) = empty {
+                let shape = game::Shape::new(i, unit);
+                if !board.check_unit_position(&board.place_new_unit(&shape)) {
+                    problems.push(format!("unit {} cannot spawn on the empty board", i));
+                }
+            }
+        }
+        problems
+    }
+
+    /// Oddities that still make a playable problem, as the official ones
+    /// have them: units given more than once, which only makes the source
+    /// pick them more often, and units whose members do not touch.
+    pub fn warnings(&self) -> Vec<String> {
+        let mut warnings = Vec::new();
+        for (i, unit) in self.units.iter().enumerate() {
+            if let Some(j) = self.units[..i].iter().position(|other| other == unit) {
+                warnings.push(format!("unit {} is the same as unit {}", i, j));
+            }
+            let cells = unit.members.iter().map(|&c| hex2d::Coordinate::from(c)).collect();
+            if !unit.members.is_empty() && !is_connected(&cells) {
+                warnings.push(format!("unit {} is not connected", i));
+            }
+        }
+        warnings
+    }
+
+    fn validate(&self) -> Result<()> {
+        let problems = self.check();
+        if !problems.is_empty() {
+            return Err(Error::Geometry(problems.join("; ")))
+        }
+        Ok(())
+    }
+
+    pub fn games(&self, phrases: &Vec<String>) -> Result<Vec<game::Game>> {
+        self.games_for_seeds(&self.sourceSeeds, phrases)
+    }
+
+    pub fn game_for_seed(&self, seed: u64, phrases: &Vec<String>) -> Result<game::Game> {
+        self.games_for_seeds(&[seed], phrases).map(|mut games| games.pop().unwrap())
+    }
+
+    /// Games for each of `seeds`, checking the problem once for all of them.
+    pub fn games_for_seeds(&self, seeds: &[u64], phrases: &Vec<String>)
+                           -> Result<Vec<game::Game>> {
+        try!(self.validate());
+        seeds.iter().map(|&s| self.game(s, phrases)).collect()
     }
 
-    pub fn game_for_seed(&self, seed: u64) -> game::Game {
-        let board = board::Board::new(self.width, self.height,
-                                      self.filled.iter().map(|c| (c.x, c.y)));
+    /// Game for `seed`, taking the problem to be valid.
+    fn game(&self, seed: u64, phrases: &Vec<String>) -> Result<game::Game> {
+        let board = try!(board::Board::new(self.width, self.height,
+                                           self.filled.iter().map(|c| (c.x, c.y))));
 
 
-        let units: Vec<Vec<hex2d::Coordinate>> = self.units.iter().map(|u| {
-            u.members.iter()
+        let shapes = self.units.iter().enumerate().map(|(i, u)| {
+            let cells = u.members.iter()
                     .map(|&c| hex2d::Coordinate::from(c) - hex2d::Coordinate::from(u.pivot))
-                    .collect()
+                    .collect();
+            game::Shape::new(i, &cells)
         }).collect();
 
         let source = get_source_seq(self.sourceLength, seed).iter()
-            .map(|&i| units[i % units.len()].clone())
+            .map(|&i| i % self.units.len())
             .collect();
 
-        game::Game {
+        Ok(game::Game {
             board: board,
+            shapes: shapes,
             source: source,
-            seed: seed
-        }
+            seed: seed,
+            phrases: phrases.iter().map(|p| p.to_lowercase()).collect()
+        })
     }
 
 }
 
 
-#[derive(Debug, RustcDecodable, RustcEncodable)]
+#[derive(Debug, RustcDecodable, RustcEncodable, PartialEq)]
 pub struct Unit {
     pub members: Vec<Cell>,
     pub pivot: Cell
 }
 
-#[derive(Debug, RustcDecodable, RustcEncodable, Clone, Copy)]
+#[derive(Debug, RustcDecodable, RustcEncodable, Clone, Copy, PartialEq)]
 pub struct Cell {
     pub x: i32,
     pub y: i32
@@ -72,6 +173,22 @@ impl From<Cell> for hex2d::Coordinate {
     }
 }
 
+/// Whether every one of `cells` can be reached from the first through
+/// neighbouring cells.
+fn is_connected(cells: &Vec<hex2d::Coordinate>) -> bool {
+    let mut reached = vec![cells[0]];
+    let mut i = 0;
+    while i < reached.len() {
+        for n in reached[i].neighbors().iter() {
+            if cells.contains(n) && !reached.contains(n) {
+                reached.push(*n);
+            }
+        }
+        i += 1;
+    }
+    cells.iter().all(|c| reached.contains(c))
+}
+
 pub fn get_source_seq(length: usize, seed: u64) -> Vec<usize> {
     fn extract(x: u64) -> usize {
         let m = 1 << 31;
@@ -89,3 +206,33 @@ pub fn get_source_seq(length: usize, seed: u64) -> Vec<usize> {
     }
     result
 }
+
+#[test]
+fn check_reports_every_problem() {
+    let cell = |x, y| Cell { x: x, y: y };
+    let unit = |members: Vec<Cell>| Unit { pivot: members[0], members: members };
+    let board = Board {
+        id: 0,
+        units: vec![unit(vec![cell(0, 0)]),
+                    unit(vec![cell(0, 0), cell(0, 0)]),
+                    unit(vec![cell(0, 0), cell(2, 0)]),
+                    unit(vec![cell(0, 0), cell(1, 0), cell(2, 0), cell(3, 0)])],
+        width: 3,
+        height: 3,
+        filled: vec![cell(1, 1), cell(3, 0)],
+        sourceLength: 0,
+        sourceSeeds: vec![0]
+    };
+    assert_eq!(board.check(), vec![
+        "filled cell (3, 0) is off the 3x3 board",
+        "the source has no units",
+        "unit 1 has a member twice",
+        "unit 3 cannot spawn on the empty board"]);
+    assert_eq!(board.warnings(), vec!["unit 2 is not connected"]);
+    assert!(board.game_for_seed(0, &vec![]).is_err());
+
+    let board = Board { units: vec![unit(vec![cell(0, 0)]), unit(vec![cell(0, 0)])],
+                        filled: vec![], sourceLength: 1, ..board };
+    assert!(board.check().is_empty());
+    assert_eq!(board.warnings(), vec!["unit 1 is the same as unit 0"]);
+}
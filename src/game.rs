@@ -1,30 +1,54 @@
 use std::hash::{Hash, Hasher};
-use std::cmp::{Eq, PartialEq, Ord, PartialOrd, Ordering};
+use std::cmp::{self, Eq, PartialEq, Ord, PartialOrd, Ordering};
+use std::collections::HashSet;
+use std::slice;
 
 use hex2d::{Angle, Coordinate, Direction, ToCoordinate, Position, ToDirection};
 
-use board::{Board, cube_to_offset};
-use scoring::move_score;
+use board::{Board, RowMask, cube_to_offset, offset_to_cube};
+use scoring::{move_score, power_score};
+use encoder::{command_to_symbol, symbol_to_command};
 
 pub struct Game {
     pub board: Board,
-    pub source: Vec<Vec<Coordinate>>,
-    pub seed: u64
+    pub shapes: Vec<Shape>,
+    /// Indices into `shapes`.
+    pub source: Vec<usize>,
+    pub seed: u64,
+    /// Phrases of power, lowercased.
+    pub phrases: Vec<String>
 }
 
 #[derive(RustcEncodable)]
-struct UnitState {
+pub struct UnitState {
     pivot: (i32, i32),
     cells: Vec<(i32, i32)>
 }
 
 #[derive(RustcEncodable)]
-struct GameState {
+pub struct GameState {
     pub board: Board,
     pub unit: UnitState,
     pub previous_move: String
 }
 
+/// `GameState` with what the command leading to it did, for debugging.
+#[derive(RustcEncodable)]
+pub struct AnnotatedState {
+    pub board: Board,
+    pub unit: UnitState,
+    pub previous_move: String,
+    /// Commands issued so far.
+    pub turn: usize,
+    /// Index in the source of the unit in play.
+    pub unit_index: usize,
+    pub move_score: i32,
+    pub power_score: i32,
+    /// Lines cleared by the command, if it locked a unit.
+    pub cleared_lines: i32,
+    /// Phrases of power the command completed.
+    pub phrases: Vec<String>
+}
 
 #[derive(Clone)]
 pub struct GamePosition<'a> {
@@ -34,14 +58,81 @@ pub struct GamePosition<'a> {
     pub sum_unit_size: i32,
     pub next_source: usize,
     pub cleared_lines_prev: i32,
-    pub score: i32,
-    pub previous_move: Option<Command>
+    pub move_score: i32,
+    /// Occurrences of each of `game.phrases` in the emitted commands,
+    /// overlapping ones included.
+    pub power_counts: Vec<i32>,
+    /// Tail of the emitted commands, long enough to complete any phrase.
+    recent: String,
+    pub previous_move: Option<Command>,
+    /// Placements the current unit has occupied since it spawned.
+    pub visited: HashSet<Placement>
+}
+
+/// Result of applying a single command to a `GamePosition`. Every
+/// variant carries the position the command led to; for the terminal
+/// ones this is where the game ended, with its final score.
+pub enum StepOutcome<'a> {
+    /// The unit moved or rotated to a free placement.
+    Moved(GamePosition<'a>),
+    /// The unit was locked and the next one spawned.
+    Locked(GamePosition<'a>),
+    /// The last unit of the source was locked.
+    SourceExhausted(GamePosition<'a>),
+    /// The unit was locked, but the next one overlaps filled cells.
+    SpawnBlocked(GamePosition<'a>),
+    /// The command would return the unit to a placement it has already
+    /// occupied. The official scorer zeroes such a solution, the position
+    /// is the one before the offending command.
+    IllegalRepeat(GamePosition<'a>)
+}
+
+impl<'a> StepOutcome<'a> {
+    pub fn position(&self) -> &GamePosition<'a> {
+        match *self {
+            StepOutcome::Moved(ref p) |
+            StepOutcome::Locked(ref p) |
+            StepOutcome::SourceExhausted(ref p) |
+            StepOutcome::SpawnBlocked(ref p) |
+            StepOutcome::IllegalRepeat(ref p) => p
+        }
+    }
+
+    pub fn into_position(self) -> GamePosition<'a> {
+        match self {
+            StepOutcome::Moved(p) |
+            StepOutcome::Locked(p) |
+            StepOutcome::SourceExhausted(p) |
+            StepOutcome::SpawnBlocked(p) |
+            StepOutcome::IllegalRepeat(p) => p
+        }
+    }
+
+    /// Returns `true` if no further commands can be applied.
+    pub fn is_game_over(&self) -> bool {
+        match *self {
+            StepOutcome::Moved(_) | StepOutcome::Locked(_) => false,
+            _ => true
+        }
+    }
+
+    pub fn describe(&self) -> &'static str {
+        match *self {
+            StepOutcome::Moved(_)           => "moved",
+            StepOutcome::Locked(_)          => "locked",
+            StepOutcome::SourceExhausted(_) => "source exhausted",
+            StepOutcome::SpawnBlocked(_)    => "spawn blocked",
+            StepOutcome::IllegalRepeat(_)   => "illegal repeat"
+        }
+    }
 }
 
 impl<'a> GamePosition<'a> {
-    pub fn next_unit(&self) -> Option<Unit<'a>> {
-        self.game.source.get(self.next_source)
-            .map(|u| self.board.place_new_unit(u))
+    /// Shapes of up to `n` units coming after the current one.
+    pub fn upcoming_shapes(&self, n: usize) -> Vec<&'a Shape> {
+        self.game.source.iter().skip(self.next_source).take(n)
+            .map(|&i| &self.game.shapes[i])
+            .collect()
     }
 
     pub fn to_state(&self) -> GameState {
@@ -49,61 +140,189 @@ impl<'a> GamePosition<'a> {
         GameState {
             board: self.board.clone(),
             unit: UnitState {
-                pivot: cube_to_offset(&self.unit.position.to_coordinate()),
+                pivot: self.unit.pivot(),
                 cells: cells
             },
             previous_move: self.previous_move.map(|c| c.to_string()).unwrap_or("".to_string())
         }
     }
 
+    /// `to_state` annotated with what happened since `previous`, the
+    /// position one command earlier, if there is one.
+    pub fn annotate(&self, turn: usize, previous: Option<&GamePosition>) -> AnnotatedState {
+        let state = self.to_state();
+        let (cleared_lines, phrases) = match previous {
+            Some(p) => {
+                let locked = p.next_source != self.next_source;
+                let phrases = self.game.phrases.iter()
+                    .zip(self.power_counts.iter().zip(p.power_counts.iter()))
+                    .filter(|&(_, (now, before))| now > before)
+                    .map(|(phrase, _)| phrase.clone())
+                    .collect();
+                (if locked { self.cleared_lines_prev } else { 0 }, phrases)
+            }
+            None => (0, Vec::new())
+        };
+        AnnotatedState {
+            board: state.board,
+            unit: state.unit,
+            previous_move: state.previous_move,
+            turn: turn,
+            unit_index: self.unit_index(),
+            move_score: self.move_score,
+            power_score: self.power_score(),
+            cleared_lines: cleared_lines,
+            phrases: phrases
+        }
+    }
+
+    /// Index in `game.source` of `unit`. The position after the last lock
+    /// keeps the locked unit, as there is none to spawn.
+    pub fn unit_index(&self) -> usize {
+        cmp::min(self.next_source, self.game.source.len()) - 1
+    }
+
+    pub fn power_score(&self) -> i32 {
+        self.game.phrases.iter()
+            .zip(self.power_counts.iter())
+            .map(|(p, &reps)| power_score(p.chars().count() as i32, reps))
+            .fold(0, |a, b| a + b)
+    }
+
+    pub fn total_score(&self) -> i32 {
+        self.move_score + self.power_score()
+    }
+
+    /// Appends `sym` to the emitted commands and counts the phrases it
+    /// completes.
+    fn emit(&self, sym: char) -> (Vec<i32>, String) {
+        let mut recent = self.recent.clone();
+        recent.extend(sym.to_lowercase());
+        let counts = self.game.phrases.iter()
+            .zip(self.power_counts.iter())
+            .map(|(p, &reps)| if recent.ends_with(&p[..]) { reps + 1 } else { reps })
+            .collect();
+        let keep = self.game.phrases.iter()
+            .map(|p| p.chars().count())
+            .max().unwrap_or(1) - 1;
+        let len = recent.chars().count();
+        if len > keep {
+            recent = recent.chars().skip(len - keep).collect();
+        }
+        (counts, recent)
+    }
+
+    /// The position before the first command. The game may be over before
+    /// it starts, see `is_spawn_blocked`.
     pub fn start(g: &Game) -> GamePosition {
+        let unit = g.board.place_new_unit(&g.shapes[g.source[0]]);
+        let mut visited = HashSet::new();
+        visited.insert(unit.placement());
         GamePosition {
             game: g,
             board: g.board.clone(),
-            unit: g.board.place_new_unit(&g.source[0]),
+            unit: unit,
             sum_unit_size: 0,
             next_source: 1,
             cleared_lines_prev: 0,
-            score: 0,
-            previous_move: None
+            move_score: 0,
+            power_counts: vec![0; g.phrases.len()],
+            recent: String::new(),
+            previous_move: None,
+            visited: visited
         }
     }
 
-    pub fn lock_current_unit(&self, c: Command) -> Option<GamePosition<'a>> {
-        if !(self.next_source < self.game.source.len()) {
-            return None
-        }
+    /// Returns `true` if the current unit overlaps filled cells, as it does
+    /// after `SpawnBlocked` or when the first unit cannot spawn.
+    pub fn is_spawn_blocked(&self) -> bool {
+        !self.board.check_unit_position(&self.unit)
+    }
+
+    /// Locks the current unit with the command `c` and spawns the next one.
+    /// When the source is exhausted the lock is still applied and scored;
+    /// the terminal position then keeps the locked unit as `unit`.
+    pub fn lock_current_unit(&self, c: Command) -> StepOutcome<'a> {
+        self.lock_as(c, command_to_symbol(&c))
+    }
 
+    fn lock_as(&self, c: Command, sym: char) -> StepOutcome<'a> {
+        let (power_counts, recent) = self.emit(sym);
         let (board, cleared_lines) = self.board.lock_unit(&self.unit);
-        let unit = self.board.place_new_unit(&self.game.source[self.next_source]);
         let sum_unit_size = self.sum_unit_size + self.unit.size();
-        let new_score = self.score + move_score(self.unit.size(),
-                                                cleared_lines,
-                                                self.cleared_lines_prev);
-        Some(GamePosition {
+        let new_score = self.move_score + move_score(self.unit.size(),
+                                                     cleared_lines,
+                                                     self.cleared_lines_prev);
+        let source_exhausted = !(self.next_source < self.game.source.len());
+        let unit = if source_exhausted {
+            self.unit.clone()
+        } else {
+            board.place_new_unit(&self.game.shapes[self.game.source[self.next_source]])
+        };
+        let mut visited = HashSet::new();
+        visited.insert(unit.placement());
+        let spawned = board.check_unit_position(&unit);
+        let position = GamePosition {
             game: self.game,
             board: board,
             unit: unit,
             sum_unit_size: sum_unit_size,
             next_source: self.next_source + 1,
             cleared_lines_prev: cleared_lines,
-            score: new_score,
-            previous_move: Some(c)
+            move_score: new_score,
+            power_counts: power_counts,
+            recent: recent,
+            previous_move: Some(c),
+            visited: visited
+        };
+        if source_exhausted {
+            StepOutcome::SourceExhausted(position)
+        } else if spawned {
+            StepOutcome::Locked(position)
+        } else {
+            StepOutcome::SpawnBlocked(position)
+        }
+    }
+
+    /// Applies `c`, emitting the first symbol that encodes it.
+    pub fn step(&self, c: Command) -> StepOutcome<'a> {
+        self.step_as(c, command_to_symbol(&c))
+    }
+
+    /// Applies the command encoded by `sym`, or returns `None` if it
+    /// encodes none.
+    pub fn step_symbol(&self, sym: char) -> Option<StepOutcome<'a>> {
+        symbol_to_command(sym).map(|c| {
+            self.step_as(c, sym.to_lowercase().next().unwrap_or(sym))
         })
     }
 
-    pub fn step(&self, c: Command) -> Option<GamePosition<'a>> {
+    fn step_as(&self, c: Command, sym: char) -> StepOutcome<'a> {
         let unit = self.unit.apply(&c);
-        if self.board.check_unit_position(&unit) {
-            Some(GamePosition {
-                unit: unit,
-                board: self.board.clone(),
-                previous_move: Some(c),
-                ..*self
-            })
-        } else {
-            self.lock_current_unit(c)
+        if !self.board.check_unit_position(&unit) {
+            return self.lock_as(c, sym)
+        }
+
+        let placement = unit.placement();
+        if self.visited.contains(&placement) {
+            return StepOutcome::IllegalRepeat(self.clone())
         }
+        let (power_counts, recent) = self.emit(sym);
+        let mut visited = self.visited.clone();
+        visited.insert(placement);
+        StepOutcome::Moved(GamePosition {
+            game: self.game,
+            board: self.board.clone(),
+            unit: unit,
+            sum_unit_size: self.sum_unit_size,
+            next_source: self.next_source,
+            cleared_lines_prev: self.cleared_lines_prev,
+            move_score: self.move_score,
+            power_counts: power_counts,
+            recent: recent,
+            previous_move: Some(c),
+            visited: visited
+        })
     }
 }
 
@@ -154,21 +373,153 @@ pub static ALL_COMMANDS : [Command; 6] = [
     Command::Rotate(Angle::Right)  // CW
 ];
 
+/// Canonical identity of a unit on the board. Orientations that cover the
+/// same cells around the same pivot share an orientation index, so two
+/// units have equal placements exactly when they cover the same cells
+/// and have the same pivot. The no-repeat rule and the searches compare
+/// units by placement.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub struct Placement {
+    shape: usize,
+    orientation: usize,
+    pivot: (i32, i32)
+}
+
+/// A source unit with its orientations worked out up front. Cells are kept
+/// as offsets from the pivot in offset coordinates; since those depend on
+/// whether the pivot sits in an even or an odd row, every orientation is
+/// stored once for each parity.
+#[derive(Debug)]
+pub struct Shape {
+    pub id: usize,
+    size: i32,
+    /// First member for each of the six directions, relative to the pivot
+    /// in cube coordinates.
+    corners: [Coordinate; 6],
+    /// Distinct orientations. Rotating by `orientations.len()` steps covers
+    /// the same cells, so symmetric shapes have fewer than six.
+    orientations: Vec<Orientation>
+}
+
+#[derive(Debug)]
+struct Orientation {
+    cells: [Vec<(i32, i32)>; 2],
+    masks: [Vec<RowMask>; 2],
+    /// `(left, right, top)` borders of `cells`.
+    bounds: [(i32, i32, i32); 2]
+}
+
+impl Shape {
+    /// `cells` are the members relative to the pivot, in cube coordinates.
+    pub fn new(id: usize, cells: &Vec<Coordinate>) -> Shape {
+        let rotated: Vec<Vec<Coordinate>> = (0..6).map(|i| {
+            let angle = Direction::from_int(i) - Direction::from_int(0);
+            cells.iter().map(|c| c.rotate_around_zero(angle)).collect()
+        }).collect();
+        let period = [1, 2, 3, 6].iter().cloned()
+            .find(|&k| k == 6 || same_cells(&rotated[0], &rotated[k]))
+            .unwrap();
+
+        let mut corners = [(0, 0).to_coordinate(); 6];
+        for (corner, cells) in corners.iter_mut().zip(rotated.iter()) {
+            if let Some(&c) = cells.first() {
+                *corner = c;
+            }
+        }
+
+        Shape {
+            id: id,
+            size: cells.len() as i32,
+            corners: corners,
+            orientations: rotated[..period].iter().map(|cells| {
+                let even = Shape::offsets(cells, 0);
+                let odd = Shape::offsets(cells, 1);
+                Orientation {
+                    masks: [row_masks(&even), row_masks(&odd)],
+                    bounds: [bounds(&even), bounds(&odd)],
+                    cells: [even, odd]
+                }
+            }).collect()
+        }
+    }
+
+    /// Offsets of `cells` from a pivot in a row of the given parity.
+    fn offsets(cells: &Vec<Coordinate>, parity: i32) -> Vec<(i32, i32)> {
+        let pivot = offset_to_cube(&(0, parity));
+        cells.iter().map(|&c| {
+            let (x, y) = cube_to_offset(&(pivot + c));
+            (x, y - parity)
+        }).collect()
+    }
+}
+
+fn same_cells(a: &Vec<Coordinate>, b: &Vec<Coordinate>) -> bool {
+    let mut a = a.clone();
+    let mut b = b.clone();
+    a.sort();
+    b.sort();
+    a == b
+}
+
+fn bounds(cells: &Vec<(i32, i32)>) -> (i32, i32, i32) {
+    let left = cells.iter().map(|&(x, _y)| x).min().unwrap_or(0);
+    let right = cells.iter().map(|&(x, _y)| x).max().unwrap_or(0);
+    let top = cells.iter().map(|&(_x, y)| y).min().unwrap_or(0);
+    (left, right, top)
+}
+
+/// Groups `cells` by row into masks for `Board::fits`.
+fn row_masks(cells: &Vec<(i32, i32)>) -> Vec<RowMask> {
+    let mut cells: Vec<(i32, i32)> = cells.iter().map(|&(x, y)| (y, x)).collect();
+    cells.sort();
+    let mut masks: Vec<RowMask> = Vec::new();
+    for (y, x) in cells {
+        let same_row = match masks.last_mut() {
+            Some(m) if m.y == y && x - m.x < 64 => {
+                m.bits |= 1u64 << (x - m.x);
+                true
+            }
+            _ => false
+        };
+        if !same_row {
+            masks.push(RowMask { x: x, y: y, bits: 1 });
+        }
+    }
+    masks
+}
+
+/// Member cells of a unit, in offset coordinates.
+pub struct Cells<'b> {
+    offsets: slice::Iter<'b, (i32, i32)>,
+    pivot: (i32, i32)
+}
+
+impl<'b> Iterator for Cells<'b> {
+    type Item = (i32, i32);
+
+    fn next(&mut self) -> Option<(i32, i32)> {
+        let (x, y) = self.pivot;
+        self.offsets.next().map(|&(dx, dy)| (x + dx, y + dy))
+    }
+}
+
+/// A placed shape: which shape, its orientation and its pivot, all held
+/// in `position`.
 #[derive(Clone, Debug)]
 pub struct Unit<'a> {
-    cells: &'a Vec<Coordinate>,
+    shape: &'a Shape,
     pub position: Position,
 }
 
 impl<'a> Hash for Unit<'a> {
     fn hash<H>(&self, state: &mut H) where H: Hasher {
-        self.position.hash(state)
+        self.placement().hash(state)
     }
 }
 
 impl<'a> PartialEq for Unit<'a> {
     fn eq(&self, other: &Unit) -> bool {
-        self.position == other.position
+        self.placement() == other.placement()
     }
 }
 
@@ -176,48 +527,63 @@ impl<'a> Eq for Unit<'a> {}
 
 impl<'a> PartialOrd for Unit<'a> {
     fn partial_cmp(&self, other: &Unit) -> Option<Ordering> {
-        self.position.partial_cmp(&other.position)
+        self.placement().partial_cmp(&other.placement())
     }
 }
 
 impl<'a> Ord for Unit<'a> {
     fn cmp(&self, other: &Unit) -> Ordering {
-        self.position.cmp(&other.position)
+        self.placement().cmp(&other.placement())
     }
 }
 
 impl<'a> Unit<'a> {
-    pub fn new(cells: &'a Vec<Coordinate>) -> Unit<'a> {
+    pub fn new(shape: &'a Shape) -> Unit<'a> {
         Unit {
-            cells: cells,
+            shape: shape,
             position: Position::new((0, 0).to_coordinate(), Direction::from_int(0))
         }
     }
 
-        fn apply_to_coord(p: &Position, c: &Coordinate) -> Coordinate {
-            let angle = p.to_direction() - Direction::from_int(0);
-            let shift = p.to_coordinate();
-            c.rotate_around_zero(angle) + shift
+    /// Pivot in offset coordinates.
+    pub fn pivot(&self) -> (i32, i32) {
+        cube_to_offset(&self.position.to_coordinate())
+    }
+
+    fn orientation_index(&self) -> usize {
+        self.position.to_direction() as usize % self.shape.orientations.len()
+    }
+
+    fn orientation(&self) -> &'a Orientation {
+        &self.shape.orientations[self.orientation_index()]
+    }
+
+    fn parity(&self) -> usize {
+        (self.pivot().1 & 1) as usize
+    }
+
+    pub fn iter(&self) -> Cells<'a> {
+        Cells {
+            offsets: self.orientation().cells[self.parity()].iter(),
+            pivot: self.pivot()
         }
+    }
 
-    pub fn iter<'b>(&'b self) -> Box<Iterator<Item=(i32, i32)> + 'b> {
-        let p = self.position;
-        let it = self.cells.iter().map(move |&c| {
-                cube_to_offset(&Unit::apply_to_coord(&p, &c))
-        });
-        Box::new(it)
+    /// Row masks of the cells relative to `pivot()`.
+    pub fn row_masks(&self) -> &'a [RowMask] {
+        &self.orientation().masks[self.parity()]
     }
 
     pub fn border_top(&self) -> i32 {
-        self.iter().map(|(_x, y)| y).min().unwrap()
+        self.pivot().1 + self.orientation().bounds[self.parity()].2
     }
 
     pub fn border_left(&self) -> i32 {
-        self.iter().map(|(x, _y)| x).min().unwrap()
+        self.pivot().0 + self.orientation().bounds[self.parity()].0
     }
 
     pub fn border_right(&self) -> i32 {
-        self.iter().map(|(x, _y)| x).max().unwrap()
+        self.pivot().0 + self.orientation().bounds[self.parity()].1
     }
 
     pub fn width(&self) -> i32 {
@@ -226,8 +592,16 @@ impl<'a> Unit<'a> {
         result
     }
 
+    pub fn placement(&self) -> Placement {
+        Placement {
+            shape: self.shape.id,
+            orientation: self.orientation_index(),
+            pivot: self.pivot()
+        }
+    }
+
     pub fn size(&self) -> i32 {
-        self.cells.len() as i32
+        self.shape.size
     }
 
     pub fn apply(&self, c: &Command) -> Unit<'a> {
@@ -238,22 +612,22 @@ impl<'a> Unit<'a> {
                         d == Direction::ZY ||  // SE
                         d == Direction::ZX);   // SW
                 let position = self.position + d.to_coordinate();
-                Unit { cells: self.cells, position: position}
+                Unit { shape: self.shape, position: position}
             },
             &Command::Rotate(a) => {
                 // Read as clockwise and counterclockwise.
                 assert!(a == Angle::Right || a == Angle::Left);
                 let position = self.position + a;
-                Unit { cells: self.cells, position: position}
+                Unit { shape: self.shape, position: position}
             }
         }
     }
 
     pub fn move_corner_to<C>(&self, to: C) -> Unit<'a> where C: ToCoordinate + Copy {
-        let cell = Unit::apply_to_coord(&self.position, self.cells.first().unwrap());
-        let diff = to.to_coordinate() - cell;
+        let corner = self.shape.corners[self.position.to_direction() as usize];
+        let diff = to.to_coordinate() - (self.position.to_coordinate() + corner);
         Unit {
-            cells: self.cells,
+            shape: self.shape,
             position: self.position + diff
         }
     }
@@ -263,8 +637,156 @@ impl<'a> Unit<'a> {
     {
         let diff = target.to_coordinate() - self.position.to_coordinate();
         Unit {
-            cells: self.cells,
+            shape: self.shape,
             position: self.position + diff
         }
     }
 }
+
+/// A game of `units` single cells on a `width` by `height` board with the
+/// `filled` cells.
+#[cfg(test)]
+pub fn dots(width: usize, height: usize, filled: &[(i32, i32)], units: usize,
+            phrases: Vec<String>) -> Game {
+    Game {
+        board: Board::new(width, height, filled.iter().cloned()).unwrap(),
+        shapes: vec![Shape::new(0, &vec![(0, 0).to_coordinate()])],
+        source: vec![0; units],
+        seed: 0,
+        phrases: phrases
+    }
+}
+
+#[test]
+fn repeat_is_illegal() {
+    let game = dots(5, 5, &[], 1, vec!["p".to_string()]);
+    let start = GamePosition::start(&game);
+    let moved = match start.step(Command::Move(Direction::XY)) {
+        StepOutcome::Moved(p) => p,
+        _ => panic!("expected a move")
+    };
+    // Moving back west would complete the phrase "p", were it legal.
+    match moved.step_symbol('p').unwrap() {
+        StepOutcome::IllegalRepeat(p) => {
+            assert!(p.unit == moved.unit && p.previous_move == moved.previous_move);
+            assert!(p.power_counts == moved.power_counts);
+        }
+        _ => panic!("expected an illegal repeat")
+    }
+    // Rotating a single cell around its own pivot goes nowhere.
+    assert!(match start.step(Command::Rotate(Angle::Right)) {
+        StepOutcome::IllegalRepeat(_) => true,
+        _ => false
+    });
+}
+
+#[test]
+fn last_lock_is_scored() {
+    let game = dots(2, 2, &[(1, 1)], 1, vec![]);
+    let start = GamePosition::start(&game);
+    let pos = match start.step(Command::Move(Direction::ZY)) {
+        StepOutcome::Moved(p) => p,
+        _ => panic!("expected a move")
+    };
+    // Locking the only unit completes the bottom row.
+    match pos.step(Command::Move(Direction::ZY)) {
+        StepOutcome::SourceExhausted(p) => {
+            assert!(p.move_score == move_score(1, 1, 0));
+            assert!(p.board.n_full_rows() == 0);
+        }
+        _ => panic!("expected the game to end")
+    }
+}
+
+#[test]
+fn a_blocked_first_spawn_ends_the_game() {
+    let game = dots(3, 2, &[(1, 0)], 1, vec![]);
+    assert!(GamePosition::start(&game).is_spawn_blocked());
+    let game = dots(3, 2, &[(0, 0)], 1, vec![]);
+    assert!(!GamePosition::start(&game).is_spawn_blocked());
+}
+
+#[test]
+fn annotations_tell_what_a_command_did() {
+    let se = Command::Move(Direction::ZY);
+    let phrase = (0..2).map(|_| command_to_symbol(&se)).collect();
+    let game = dots(2, 2, &[(1, 1)], 1, vec![phrase]);
+    let start = GamePosition::start(&game);
+    let moved = start.step(se).into_position();
+    let locked = moved.step(se).into_position();
+    let first = moved.annotate(1, Some(&start));
+    assert!(first.cleared_lines == 0 && first.phrases.is_empty());
+    let last = locked.annotate(2, Some(&moved));
+    assert!(last.cleared_lines == 1 && last.phrases == game.phrases);
+    assert!(last.move_score == move_score(1, 1, 0));
+}
+
+#[test]
+fn the_last_lock_belongs_to_the_last_unit() {
+    let game = dots(3, 2, &[], 2, vec![]);
+    let mut positions = vec![GamePosition::start(&game)];
+    loop {
+        let outcome = positions.last().unwrap().step(Command::Move(Direction::ZY));
+        let over = outcome.is_game_over();
+        positions.push(outcome.into_position());
+        if over {
+            break
+        }
+    }
+    // The first unit moves down a row and locks, the second locks on top.
+    assert!(positions.len() == 4);
+    let last: Vec<usize> = (0..positions.len())
+        .filter(|&t| positions[t].unit_index() == 1)
+        .collect();
+    assert!(last == vec![2, 3]);
+    assert!(positions[3].annotate(3, Some(&positions[2])).unit_index == 1);
+}
+
+#[test]
+fn overlapping_phrases_are_counted() {
+    let game = dots(10, 10, &[], 1, vec!["ll".to_string(), "ei!".to_string()]);
+    let mut pos = GamePosition::start(&game);
+    for sym in "lLl".chars() {
+        pos = pos.step_symbol(sym).unwrap().into_position();
+    }
+    assert!(pos.power_counts == vec![2, 0]);
+    assert!(pos.power_score() == power_score(2, 2));
+    assert!(pos.total_score() == pos.move_score + pos.power_score());
+}
+
+#[test]
+fn symmetric_shapes_have_fewer_orientations() {
+    let dot = Shape::new(0, &vec![(0, 0).to_coordinate()]);
+    assert!(dot.orientations.len() == 1);
+    // Two cells on either side of the pivot.
+    let bar = Shape::new(1, &vec![(-1, 1).to_coordinate(), (1, -1).to_coordinate()]);
+    assert!(bar.orientations.len() == 3);
+    let hook = Shape::new(2, &vec![(0, 0).to_coordinate(), (1, -1).to_coordinate()]);
+    assert!(hook.orientations.len() == 6);
+
+    // Tables agree with rotating the cells directly, in rows of both parities.
+    for &(x, y) in [(3, 4), (3, 5)].iter() {
+        let mut unit = Unit::new(&hook).move_to(offset_to_cube(&(x, y)));
+        for _ in 0..6 {
+            let expected: Vec<_> = [(0, 0), (1, -1)].iter().map(|&c| {
+                let c = c.to_coordinate().rotate_around_zero(
+                    unit.position.to_direction() - Direction::from_int(0));
+                cube_to_offset(&(unit.position.to_coordinate() + c))
+            }).collect();
+            assert!(unit.iter().collect::<Vec<_>>() == expected);
+            unit = unit.apply(&Command::Rotate(Angle::Right));
+        }
+    }
+}
+
+#[test]
+fn rotating_a_symmetric_unit_keeps_its_placement() {
+    let bar = Shape::new(0, &vec![(-1, 1).to_coordinate(), (1, -1).to_coordinate()]);
+    let unit = Unit::new(&bar).move_to(offset_to_cube(&(4, 4)));
+    let turned = unit.apply(&Command::Rotate(Angle::Right))
+                     .apply(&Command::Rotate(Angle::Right))
+                     .apply(&Command::Rotate(Angle::Right));
+    assert!(turned.position != unit.position);
+    assert!(turned == unit);
+    assert!(unit.apply(&Command::Rotate(Angle::Right)) != unit);
+}
@@ -0,0 +1,20 @@
+//! The engine of our ICFP 2015 entry: problem formats, the board and game
+//! rules, scoring, encoding of commands and the strategies that play.
+
+extern crate hex2d;
+extern crate rustc_serialize;
+extern crate rand;
+extern crate simple_parallel;
+extern crate getopts;
+
+pub mod formats;
+pub mod board;
+pub mod game;
+pub mod scoring;
+pub mod strategy;
+pub mod encoder;
+pub mod evaluator;
+pub mod replay;
+pub mod tuner;
+pub mod error;
+pub mod options;
@@ -1,42 +1,46 @@
-extern crate hex2d;
+extern crate play_icfp2015;
 extern crate rustc_serialize;
-extern crate rand;
 extern crate getopts;
 extern crate simple_parallel;
 
-mod formats;
-mod board;
-mod game;
-mod scoring;
-mod strategy;
-mod encoder;
-
+use play_icfp2015::{formats, game, strategy, encoder, evaluator, replay};
+use play_icfp2015::error::{Error, Result};
+use play_icfp2015::options::{flag, parse};
 use getopts::Options;
-use std::io::Read;
-use std::fs;
+use std::io::{self, Write};
 use std::env;
+use std::process;
+use std::usize;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
 use rustc_serialize::json;
 
-// fn dirty_play<'a>(g: &'a game::Game, cmds: &Vec<game::Command>) -> Vec<game::GamePosition<'a>> {
-//     let mut cur_game_pos = game::GamePosition::start(g);
-//     let mut positions: Vec<game::GamePosition> = vec![cur_game_pos.clone()];
-//     for &cmd in cmds.iter() {
-//         if let Some(new_pos) =  cur_game_pos.step(cmd) {
-//             cur_game_pos = new_pos;
-//             positions.push(cur_game_pos.clone())
-//         } else {
-//             break
-//         }
-//     }
-//     positions
-// }
-
-// let letters = "iiiiiiiimmiiiiiimimmiiiimimimmimimimimmimimimeemimeeeemimimimimiiiiiimmeemimimimimiimimimmeemimimimmeeeemimimimmiiiiiipmiimimimeeemmimimmemimimimiiiiiimeeemimimimimeeemimimimmiiiimemimimmiiiipimeeemimimmiiiippmeeeeemimimimiiiimmimimeemimimeeeemimimiiiipmeeemmimmiimimmmimimeemimimimmeeemimiiiiipmiiiimmeeemimimiiiipmmiipmmimmiippimemimeeeemimmiipppmeeeeemimimmiimipmeeeemimimiimmeeeeemimmeemimmeeeemimiiippmiippmiiimmiimimmmmmeeeemimmiippimmimimeemimimimmeemimimimmeemimimimiimimimeeemmimimmmiiiiipimeemimimimmiiiimimmiiiiiiiimiimimimimeeemmimimimmiiiiiimimmemimimimimmimimimeemimiiiiiiiimiiiimimimiimimimmimmimimimimmeeeemimimimimmmimimimimeemimimimimmmemimimmiiiiiiimiimimimmiiiiiimeeeeemimimimimmimimimmmmemimimmeeeemimimimmiimimimmiiiiiipmeeeeemimimimimmiiiiimmemimimimimmmmimimmeeeemimimimimeeemimimimmiimimimeeemmimimmiiiiiiimimiiiiiimimmiiiiiiiimmimimimimiiiimimimeemimimimimmeeemimimimimiiiiiiimiiiimimmemimimimmeemimimimeeemmimimmiiiiiimmiiiipmmiiimmmimimeemimimeeemmimmiiiippmiiiimiiippimiimimeemimimeeeemimimiiiipmeemimimiimiimimmimeeemimimmippipmmiimemimmipimeeeemimmeemimiippimeeeeemimimmmimmmeeeemimimiiipimmiipmemimmeeeemimimiipipimmipppimeeemimmpppmmpmeeeeemimmemm";
-//     let cmds:Vec<_> = letters.chars() .map(encoder::symbols2cmd).collect();
-
-
+/// Inclusive range given to the option `name` as `FROM-TO`, where either
+/// end may be left out, or as a single number; everything if not given.
+fn range(matches: &getopts::Matches, name: &str) -> Result<(usize, usize)> {
+    let s = match matches.opt_str(name) {
+        Some(s) => s,
+        None => return Ok((0, usize::MAX))
+    };
+    let bound = |b: &str, default: usize| {
+        if b.is_empty() {
+            return Ok(default)
+        }
+        b.parse().map_err(|_| {
+            Error::Usage(format!("{}: {:?} is not a range like 10-20", flag(name), s))
+        })
+    };
+    match s.find('-') {
+        Some(i) => Ok((try!(bound(&s[..i], 0)), try!(bound(&s[i + 1..], usize::MAX)))),
+        None => {
+            let n = try!(bound(&s, usize::MAX));
+            Ok((n, n))
+        }
+    }
+}
 
-fn main() {
+fn run() -> Result<()> {
+    let started = Instant::now();
     let args: Vec<String> = env::args().collect();
 
     let mut opts = Options::new();
@@ -45,49 +49,221 @@ fn main() {
     opts.optopt("m", "", "Memory limit, in megabytes, to produce output", "NUMBER");
     opts.optopt("c", "", "Number of processor cores available", "NUMBER");
     opts.optmulti("p", "", "Phrase of power", "STRING");
-    opts.optflag("d", "", "Toggle debug mode");
+    opts.optopt("w", "", "JSON file with weights of the board evaluator", "FILENAME");
+    opts.optmulti("a", "", "Strategy: greedy (default), mcts or drop; when repeated \
+                            the best solution of each seed is kept", "NAME");
+    opts.optopt("i", "", "Playouts per unit of the mcts search (default 64)", "NUMBER");
+    opts.optopt("n", "", "Placements tried from every board of the mcts search (default 8)",
+                "NUMBER");
+    opts.optopt("k", "", "Units to look ahead, counting the current one (default 2)",
+                "NUMBER");
+    opts.optopt("b", "", "Boards kept after every unit looked ahead (default 16)",
+                "NUMBER");
+    opts.optflag("d", "", "Toggle debug mode, playing the first strategy given with -a");
+    opts.optopt("", "seed", "Seed to debug (default the first one)", "NUMBER");
+    opts.optopt("", "seed-index", "Index of the seed to debug in the problem", "NUMBER");
+    opts.optopt("", "turns", "Commands issued of the states to debug, like 10-20",
+                "RANGE");
+    opts.optopt("", "units", "Units of the source of the states to debug, like 3-5",
+                "RANGE");
+    opts.optflag("", "annotate", "Annotate debugged states with scores, cleared lines \
+                                  and phrases of power");
     opts.optflag("s", "", "Scoring mode");
+    opts.optopt("r", "", "Replay solutions from a JSON file and report on them",
+                "FILENAME");
+    opts.optflag("", "check", "Report what is wrong with the problem and exit");
     opts.optflag("h", "help", "Print help");
-    let matches = match opts.parse(&args[1..]) {
-        Ok(m)  => { m }
-        Err(f) => { panic!(f.to_string()) }
+    let matches = try!(opts.parse(&args[1..]).map_err(|f| Error::Usage(f.to_string())));
+
+    let budget = match try!(parse::<f64>(&matches, "t")) {
+        // Keep a tenth of the limit in reserve for printing the output.
+        Some(t) => {
+            let limit = (t * 900.0) as u64;
+            strategy::Budget::until(started + Duration::from_millis(limit))
+        }
+        None => strategy::Budget::unlimited()
+    };
+    let cores = try!(parse(&matches, "c")).unwrap_or(1);
+    if cores == 0 {
+        return Err(Error::Usage("-c: at least one core is needed".to_string()))
+    }
+    let budget = match try!(parse::<usize>(&matches, "m")) {
+        // Half of the limit goes to the search tables, split between the
+        // workers; the rest is for games, positions and the allocator.
+        Some(m) => {
+            let bytes = m * 1024 * 1024;
+            budget.with_memory(bytes / 2 / cores)
+        }
+        None => budget
+    };
+
+    let weights = match matches.opt_str("w") {
+        Some(path) => try!(evaluator::Weights::load(&path)),
+        None => evaluator::Weights::default()
+    };
+
+    let default = strategy::Search::default();
+    let search = strategy::Search {
+        depth: try!(parse(&matches, "k")).unwrap_or(default.depth),
+        width: try!(parse(&matches, "b")).unwrap_or(default.width)
     };
 
+    let mut mcts = strategy::Mcts::default();
+    if let Some(i) = try!(parse(&matches, "i")) {
+        mcts.playouts = i;
+    }
+    if let Some(n) = try!(parse(&matches, "n")) {
+        mcts.width = n;
+    }
+    let mut names = matches.opt_strs("a");
+    if names.is_empty() {
+        names.push(strategy::STRATEGIES[0].to_string());
+    }
+    let mut strategies = Vec::new();
+    for name in &names {
+        strategies.push(try!(strategy::by_name(name, &weights, search, mcts).ok_or_else(|| {
+            Error::Usage(format!("unknown strategy {}, try one of {}", name,
+                                 strategy::STRATEGIES.join(", ")))
+        })));
+    }
+
     let path = matches.opt_str("f").unwrap();
-    let phrases = matches.opt_strs("p");
-    let mut data = String::new();
-    fs::File::open(path).unwrap().read_to_string(&mut data).unwrap();
-    let board: formats::Board = json::decode(&data).unwrap();
-    if matches.opt_present("d") {
-        let game = board.games().into_iter().next().unwrap();
-        let (_, positions) = strategy::play(
-            &game, &phrases.iter().map(encoder::phrase_to_commands).collect());
-        // let positions = dirty_play(&game, &cmds);
-        let positions: Vec<_> = positions.iter().map(|c| c.to_state())
-            .collect();
-        println!("{}", json::encode(&positions).unwrap());
+    let phrases = try!(encoder::normalize_phrases(&matches.opt_strs("p")));
+    let board: formats::Board = try!(formats::load(&path));
+    if matches.opt_present("check") {
+        let problems = board.check();
+        for problem in &problems {
+            println!("{}: {}", path, problem);
+        }
+        for warning in board.warnings() {
+            println!("{}: warning: {}", path, warning);
+        }
+        if !problems.is_empty() {
+            return Err(Error::Geometry(format!("{} is not a valid problem", path)))
+        }
+        println!("{}: ok", path);
+        return Ok(())
+    }
+    if let Some(path) = matches.opt_str("r") {
+        let solutions: Vec<formats::Solution> = try!(formats::load(&path));
+        let seeds: Vec<u64> = solutions.iter().map(|s| s.seed).collect();
+        let games = try!(board.games_for_seeds(&seeds, &phrases));
+        let mut reports = Vec::new();
+        for (s, game) in solutions.iter().zip(games.iter()) {
+            reports.push(replay::replay_for(&board, game, s));
+        }
+        println!("{}", try!(json::encode(&reports)));
+    } else if matches.opt_present("d") {
+        let mut games = try!(board.games(&phrases));
+        let index = match (try!(parse(&matches, "seed")), try!(parse(&matches, "seed-index"))) {
+            (Some(_), Some(_)) => {
+                return Err(Error::Usage("give either --seed or --seed-index".to_string()))
+            }
+            (Some(seed), None) => {
+                try!(board.sourceSeeds.iter().position(|&s| s == seed).ok_or_else(|| {
+                    Error::Usage(format!("seed {} is not in the problem", seed))
+                }))
+            }
+            (None, Some(i)) if i >= games.len() => {
+                return Err(Error::Usage(format!("--seed-index {} is past the {} seeds",
+                                                i, games.len())))
+            }
+            (None, Some(i)) => i,
+            (None, None) => 0
+        };
+        let game = games.swap_remove(index);
+        let commands = try!(strategies[0].solve(&game, &phrases, &budget));
+        // Step through the encoded solution, so that the states count the
+        // phrases of power it spells.
+        let mut positions = vec![game::GamePosition::start(&game)];
+        let mut end = None;
+        if positions[0].is_spawn_blocked() {
+            end = Some("spawn blocked");
+        }
+        for sym in encoder::encode(&commands, &phrases).chars() {
+            if end.is_some() {
+                break
+            }
+            let outcome = positions.last().unwrap().step_symbol(sym).unwrap();
+            if outcome.is_game_over() {
+                end = Some(outcome.describe());
+            }
+            match outcome {
+                // The unit never got there; the game ends before the command.
+                game::StepOutcome::IllegalRepeat(_) => {}
+                outcome => positions.push(outcome.into_position())
+            }
+        }
+        let (first_turn, last_turn) = try!(range(&matches, "turns"));
+        let (first_unit, last_unit) = try!(range(&matches, "units"));
+        let turns: Vec<usize> = (0..positions.len()).filter(|&t| {
+            let unit = positions[t].unit_index();
+            first_turn <= t && t <= last_turn && first_unit <= unit && unit <= last_unit
+        }).collect();
+        if matches.opt_present("annotate") {
+            let states: Vec<_> = turns.iter().map(|&t| {
+                positions[t].annotate(t, t.checked_sub(1).map(|p| &positions[p]))
+            }).collect();
+            println!("{}", try!(json::encode(&states)));
+        } else {
+            let states: Vec<_> = turns.iter().map(|&t| positions[t].to_state()).collect();
+            println!("{}", try!(json::encode(&states)));
+        }
+        writeln!(&mut io::stderr(), "seed {}: {} after {} moves, move score {}",
+                 game.seed, end.unwrap_or("unfinished"),
+                 positions.len() - 1,
+                 positions.last().unwrap().move_score).unwrap();
     } else {
-        let mut solutions = Vec::new();
-        let mut score = 0;
-        let games = board.games();
-        for game in &games {
-            let (commands, positions) = strategy::play(
-                &game, &phrases.iter().map(encoder::phrase_to_commands).collect());
-            //for (i, p) in positions.iter().enumerate() {
-            //  println!("turn: {} score: {}, sum_size: {}", i, p.score, p.sum_unit_size);
-            //}
-            score += positions.last().unwrap().score;
-            solutions.push(formats::Solution {
-                problemId: board.id,
-                seed: game.seed,
-                tag: "beeter".to_string(),
-                solution: encoder::encode(&commands, &phrases)
-            });
+        let games = try!(board.games(&phrases));
+        let mut pool = simple_parallel::Pool::new(cores);
+        let started = AtomicUsize::new(0);
+        let results: Result<Vec<(formats::Solution, Vec<i32>)>> = pool.map(games.iter(), &|game| {
+            // Seeds still waiting for a worker share the remaining time in
+            // waves of `cores`.
+            let i = started.fetch_add(1, Ordering::SeqCst);
+            let waves = (games.len() - i + cores - 1) / cores;
+            let share = budget.share(waves as u32);
+            let mut best: Option<formats::Solution> = None;
+            let mut scores = Vec::new();
+            for (k, strategy) in strategies.iter().enumerate() {
+                let share = share.share((strategies.len() - k) as u32);
+                let commands = try!(strategy.solve(&game, &phrases, &share));
+                let solution = formats::Solution {
+                    problemId: board.id,
+                    seed: game.seed,
+                    tag: "beeter".to_string(),
+                    solution: encoder::encode(&commands, &phrases)
+                };
+                let score = replay::replay(&game, &solution).score;
+                if scores.iter().all(|&s| score > s) {
+                    best = Some(solution);
+                }
+                scores.push(score);
+            }
+            Ok((best.unwrap(), scores))
+        }).collect();
+        let results = try!(results);
+        let best = |scores: &Vec<i32>| *scores.iter().max().unwrap();
+        let score = results.iter().map(|&(_, ref s)| best(s)).fold(0, |a, b| a + b);
+        if strategies.len() > 1 && matches.opt_present("s") {
+            for (k, name) in names.iter().enumerate() {
+                let score = results.iter().map(|&(_, ref s)| s[k]).fold(0, |a, b| a + b);
+                println!("{}: {}", name, score / games.len() as i32);
+            }
         }
+        let solutions: Vec<_> = results.into_iter().map(|(s, _)| s).collect();
         if matches.opt_present("s") {
             println!("score: {}", score / games.len() as i32);
         } else {
-            println!("{}", json::encode(&solutions).unwrap());
+            println!("{}", try!(json::encode(&solutions)));
         }
     }
+    Ok(())
+}
+
+fn main() {
+    if let Err(e) = run() {
+        writeln!(&mut io::stderr(), "play_icfp2015: {}", e).unwrap();
+        process::exit(e.exit_code());
+    }
 }
@@ -0,0 +1,22 @@
+//! Command line helpers shared by the binaries.
+
+use std::str::FromStr;
+
+use getopts::Matches;
+
+use error::{Error, Result};
+
+/// `-n` for a short option, `--name` for a long one.
+pub fn flag(name: &str) -> String {
+    format!("{}{}", if name.len() > 1 { "--" } else { "-" }, name)
+}
+
+/// Value of the option `name`, if given.
+pub fn parse<T: FromStr>(matches: &Matches, name: &str) -> Result<Option<T>> {
+    match matches.opt_str(name) {
+        None => Ok(None),
+        Some(s) => s.parse().map(Some).map_err(|_| {
+            Error::Usage(format!("{}: {:?} is not a number", flag(name), s))
+        })
+    }
+}
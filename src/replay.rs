@@ -0,0 +1,179 @@
+use encoder::{DecodeError, is_ignored};
+use formats::{self, Solution};
+use game::{Game, GamePosition, StepOutcome};
+
+/// What happened when a solution string was played back.
+#[derive(RustcEncodable)]
+pub struct Report {
+    pub problem_id: u64,
+    pub seed: u64,
+    pub tag: String,
+    pub move_score: i32,
+    pub power_score: i32,
+    /// Official score: zero if any rule was violated.
+    pub score: i32,
+    pub phrases: Vec<PhraseHits>,
+    /// Outcome that ended the game, or "unfinished" if the solution ran
+    /// out of commands first.
+    pub end: String,
+    /// Number of commands applied, the one that ended the game included.
+    pub commands: usize,
+    pub violations: Vec<String>
+}
+
+#[derive(RustcEncodable)]
+pub struct PhraseHits {
+    pub phrase: String,
+    pub count: i32
+}
+
+/// Plays `solution` through `GamePosition::step_symbol` from the start.
+pub fn replay(g: &Game, solution: &Solution) -> Report {
+    let mut position = GamePosition::start(g);
+    let mut end = None;
+    let mut violations = Vec::new();
+    let mut commands = 0;
+    // Symbols past an unknown one are not commands after the end.
+    let mut unknown_symbol = false;
+    let mut symbols = solution.solution.chars().enumerate()
+        .filter(|&(_, sym)| !is_ignored(sym));
+    if position.is_spawn_blocked() {
+        end = Some("spawn blocked");
+    }
+    while end.is_none() {
+        let (i, sym) = match symbols.next() {
+            Some(s) => s,
+            None => break
+        };
+        commands += 1;
+        let outcome = match position.step_symbol(sym) {
+            Some(outcome) => outcome,
+            None => {
+                let error = DecodeError::UnknownSymbol { symbol: sym, position: i };
+                violations.push(format!("command {}: {}", commands, error));
+                end = Some("unknown symbol");
+                unknown_symbol = true;
+                break
+            }
+        };
+        if outcome.is_game_over() {
+            if let StepOutcome::IllegalRepeat(_) = outcome {
+                violations.push(format!("command {} ('{}') repeats a placement",
+                                        commands, sym));
+            }
+            end = Some(outcome.describe());
+        }
+        position = outcome.into_position();
+    }
+
+    let extra = symbols.count();
+    if extra > 0 && !unknown_symbol {
+        violations.push(format!("{} commands after the end of the game", extra));
+    }
+
+    let phrases = g.phrases.iter().zip(position.power_counts.iter())
+        .map(|(p, &count)| PhraseHits { phrase: p.clone(), count: count })
+        .collect();
+    let total = if violations.is_empty() { position.total_score() } else { 0 };
+    Report {
+        problem_id: solution.problemId,
+        seed: solution.seed,
+        tag: solution.tag.clone(),
+        move_score: position.move_score,
+        power_score: position.power_score(),
+        score: total,
+        phrases: phrases,
+        end: end.unwrap_or("unfinished").to_string(),
+        commands: commands,
+        violations: violations
+    }
+}
+
+/// `replay` of `solution` for `g`, a game of `problem`; a solution for
+/// another problem, or for a seed not in it, scores nothing.
+pub fn replay_for(problem: &formats::Board, g: &Game, solution: &Solution) -> Report {
+    let mut report = replay(g, solution);
+    if solution.problemId != problem.id {
+        report.violations.push(format!("solution is for problem {}", solution.problemId));
+        report.score = 0;
+    }
+    if !problem.sourceSeeds.contains(&solution.seed) {
+        report.violations.push(format!("seed {} is not in the problem", solution.seed));
+        report.score = 0;
+    }
+    report
+}
+
+/// Official score of the solution string `solution` for `g`.
+pub fn score(g: &Game, solution: &str) -> i32 {
+    replay(g, &Solution {
+        problemId: 0,
+        seed: g.seed,
+        tag: String::new(),
+        solution: solution.to_string()
+    }).score
+}
+
+#[cfg(test)]
+fn problem(height: usize) -> formats::Board {
+    formats::Board {
+        id: 1,
+        units: vec![formats::Unit {
+            members: vec![formats::Cell { x: 0, y: 0 }],
+            pivot: formats::Cell { x: 0, y: 0 }
+        }],
+        width: 5,
+        height: height,
+        filled: vec![],
+        sourceLength: 1,
+        sourceSeeds: vec![0]
+    }
+}
+
+#[cfg(test)]
+fn solution(problem_id: u64, seed: u64, commands: &str) -> Solution {
+    Solution {
+        problemId: problem_id,
+        seed: seed,
+        tag: String::new(),
+        solution: commands.to_string()
+    }
+}
+
+#[test]
+fn repeating_a_placement_scores_nothing() {
+    let game = problem(5).game_for_seed(0, &vec![]).unwrap();
+    // East, then back west.
+    let report = replay(&game, &solution(1, 0, "bp"));
+    assert!(report.end == "illegal repeat");
+    assert!(report.violations == vec!["command 2 ('p') repeats a placement"]);
+    assert!(report.score == 0);
+}
+
+#[test]
+fn commands_after_the_end_are_reported() {
+    // The only unit locks at once on a single row.
+    let game = problem(1).game_for_seed(0, &vec![]).unwrap();
+    let report = replay(&game, &solution(1, 0, "ab\na"));
+    assert!(report.commands == 1);
+    assert!(report.violations == vec!["2 commands after the end of the game"]);
+    assert!(report.score == 0);
+}
+
+#[test]
+fn unfinished_solutions_are_scored() {
+    let game = problem(5).game_for_seed(0, &vec!["b".to_string()]).unwrap();
+    let report = replay(&game, &solution(1, 0, "b"));
+    assert!(report.end == "unfinished" && report.violations.is_empty());
+    assert!(report.score == report.power_score && report.score > 0);
+}
+
+#[test]
+fn solutions_for_other_problems_or_seeds_score_nothing() {
+    let problem = problem(5);
+    let game = problem.game_for_seed(7, &vec!["b".to_string()]).unwrap();
+    let report = replay_for(&problem, &game, &solution(2, 7, "b"));
+    assert!(report.violations == vec!["solution is for problem 2",
+                                      "seed 7 is not in the problem"]);
+    assert!(report.score == 0);
+}
@@ -10,7 +10,7 @@ pub fn move_score(size : i32, ls : i32, ls_old : i32) -> i32 {
 pub fn power_score(len : i32, reps : i32) -> i32 {
     let power_bonus = if reps > 0 { 300 }
                       else        { 0 };
-    2 * len + reps + power_bonus
+    2 * len * reps + power_bonus
 }
 
 #[test]
@@ -22,6 +22,6 @@ fn move_score_test() {
 
 #[test]
 fn power_score_test() {
-    assert!(power_score(10, 0) == 20);
-    assert!(power_score(15, 2) == 332);
+    assert!(power_score(10, 0) == 0);
+    assert!(power_score(15, 2) == 360);
 }
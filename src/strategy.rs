@@ -1,23 +1,88 @@
 use std::collections::{VecDeque, BinaryHeap, HashMap, HashSet};
 use std::io::{self, Write};
+use std::cmp;
 use std::i32;
+use std::mem::size_of;
+use std::time::{Duration, Instant};
 
-use hex2d::Angle;
+use hex2d::{Angle, Direction};
 
-use game::{Command, Unit, ALL_COMMANDS};
-use game::{Game, GamePosition};
-use board::{Board, offset_to_cube, cube_to_offset};
+use game::{Command, Placement, Shape, Unit, ALL_COMMANDS};
+use game::{Game, GamePosition, StepOutcome};
+#[cfg(test)]
+use game::dots;
+use board::{Board, offset_to_cube};
+use encoder::{encode, phrase_to_commands};
+use evaluator::Evaluator;
+use error::{Error, Result};
+use replay;
+use scoring::{move_score, power_score};
 
-fn xy(unit: &Unit) -> Vec<(i32, i32)> {
-    let mut acc: Vec<(i32, i32)> = unit.iter().collect();
-    let pivot = unit.position.coord;
-    acc.push(cube_to_offset(&pivot));
-    acc.sort();
-    acc
+/// Time and memory budget of a search. Searches poll `expired` and return
+/// the best they have so far once it fires; they estimate the size of
+/// their tables and fall back to something smaller once it does not
+/// `fit`.
+#[derive(Clone, Copy, Debug)]
+pub struct Budget {
+    deadline: Option<Instant>,
+    memory: Option<usize>
 }
 
-pub fn route_because_it_works(source: &Unit, target: &Unit,
-                              board: &Board) -> Option<Vec<Command>> {
+impl Budget {
+    pub fn unlimited() -> Budget {
+        Budget { deadline: None, memory: None }
+    }
+
+    pub fn until(deadline: Instant) -> Budget {
+        Budget { deadline: Some(deadline), memory: None }
+    }
+
+    /// Limits every search structure to about `bytes` bytes.
+    pub fn with_memory(self, bytes: usize) -> Budget {
+        Budget { memory: Some(bytes), ..self }
+    }
+
+    pub fn fits(&self, bytes: usize) -> bool {
+        self.memory.map_or(true, |m| bytes <= m)
+    }
+
+    pub fn is_limited(&self) -> bool {
+        self.deadline.is_some()
+    }
+
+    pub fn expired(&self) -> bool {
+        self.deadline.map_or(false, |d| Instant::now() >= d)
+    }
+
+    pub fn remaining(&self) -> Option<Duration> {
+        self.deadline.map(|d| {
+            let now = Instant::now();
+            if now < d { d - now } else { Duration::new(0, 0) }
+        })
+    }
+
+    /// A fair part of the remaining time for one of `n` equal tasks.
+    pub fn share(&self, n: u32) -> Budget {
+        match self.remaining() {
+            None => *self,
+            Some(left) => Budget {
+                deadline: Some(Instant::now() + left / n),
+                ..*self
+            }
+        }
+    }
+}
+
+/// Rough size in bytes of a hash table with `len` entries, counting the
+/// slack a table keeps for growth.
+pub fn table_bytes<K, V>(len: usize) -> usize {
+    2 * len * (size_of::<K>() + size_of::<V>() + size_of::<u64>())
+}
+
+/// Shortest path from `source` to `target`, then a lock. `None` if there is
+/// none, or if its table outgrows `budget` first.
+pub fn route_because_it_works(source: &Unit, target: &Unit, board: &Board,
+                              budget: &Budget) -> Result<Option<Vec<Command>>> {
     let mut q = VecDeque::new();
     q.push_back(source.clone());
     let mut parents: HashMap<Unit, (Command, Unit)> = HashMap::new();
@@ -30,6 +95,11 @@ pub fn route_because_it_works(source: &Unit, target: &Unit,
         if tip == *target {
             break;
         }
+        let used = table_bytes::<Unit, (Command, Unit)>(parents.len())
+            + q.len() * size_of::<Unit>();
+        if !budget.fits(used) {
+            return Ok(None)
+        }
 
         for cj in ALL_COMMANDS.iter() {
             let next = tip.apply(cj);
@@ -41,7 +111,7 @@ pub fn route_because_it_works(source: &Unit, target: &Unit,
     }
 
     if !parents.contains_key(target) {
-        return None;  // no path found.
+        return Ok(None);  // no path found.
     }
 
     let mut path = Vec::new();
@@ -58,53 +128,146 @@ pub fn route_because_it_works(source: &Unit, target: &Unit,
         let locked = target.apply(c);
         if !board.check_unit_position(&locked) {
             path.push(*c);
-            return Some(path)
+            return Ok(Some(path))
         }
     }
 
-    panic!("sai wat?");
+    Err(Error::Search("no command locks the unit at its target".to_string()))
 }
 
-/// Find a sequence of commands which transform `source` to `target`.
-pub fn route(source: &Unit, target: &Unit, board: &Board,
-             phrases: &Vec<Vec<Command>>) -> Option<Vec<Command>> {
-    let max = phrases.iter().map(|v| v.len()).max().unwrap_or(0);
-    let penalty = 1000;
+/// Commands leading from `source` to `tip` along `parents`, and the
+/// placements they go through; `None` if they go through one twice.
+fn path_to<'a>(parents: &HashMap<Unit<'a>, (Command, Unit<'a>)>, source: &Unit<'a>,
+               tip: &Unit<'a>) -> Option<(Vec<Command>, HashSet<Placement>)> {
+    let mut seen: HashSet<Placement> = HashSet::new();
+    seen.insert(tip.placement());
+
+    let mut path = Vec::new();
+    let mut tip = tip;
+    while tip != source {
+        assert!(parents.contains_key(&tip));
+        let (c, ref next) = parents[tip];
+        path.push(c);
+        if !seen.insert(next.placement()) {
+            return None
+        }
+        tip = next;
+    }
+    path.reverse();
+    Some((path, seen))
+}
+
+/// Whether `commands` only move `unit` to free placements of `board`,
+/// none of them twice.
+fn moves_freely(unit: &Unit, commands: &[Command], board: &Board) -> bool {
+    let mut seen = HashSet::new();
+    seen.insert(unit.placement());
+    let mut unit = unit.clone();
+    commands.iter().all(|c| {
+        unit = unit.apply(c);
+        board.check_unit_position(&unit) && seen.insert(unit.placement())
+    })
+}
+
+/// Find a sequence of commands which transform the unit of `position` to
+/// `target` and lock it there, spelling as much of it as it can with
+/// phrases. Every command outside a phrase costs more than any phrase; a
+/// phrase costs less the more points it adds, and phrases not used yet in
+/// the game, still worth the bonus, cost the least. Placements the unit has
+/// already been at are avoided.
+/// A phrase may lock the unit at the target before it ends; the next unit
+/// then has to spawn, and the tail move it around without locking or
+/// repeating it.
+/// Falls back to `route_because_it_works` if its tables outgrow `budget`.
+pub fn route<'a>(position: &GamePosition<'a>, target: &Unit<'a>,
+                 phrases: &Vec<Vec<Command>>,
+                 budget: &Budget) -> Result<Option<Vec<Command>>> {
+    let (source, board) = (&position.unit, &position.board);
+    let free = |u: &Unit| {
+        board.check_unit_position(u) && !position.visited.contains(&u.placement())
+    };
+    let spawned = position.upcoming_shapes(1).into_iter().next().map(|shape| {
+        let (board, _) = board.lock_unit(target);
+        let unit = board.place_new_unit(shape);
+        (board, unit)
+    });
+    let counts = &position.power_counts;
+    let points: Vec<i32> = phrases.iter().zip(counts.iter()).map(|(p, &reps)| {
+        let len = p.len() as i32;
+        power_score(len, reps + 1) - power_score(len, reps)
+    }).collect();
+    // Phrases cost less the more points they add once used; those still
+    // worth the bonus cost next to nothing.
+    let most = phrases.iter().map(|p| {
+        let len = p.len() as i32;
+        power_score(len, 2) - power_score(len, 1)
+    }).max().unwrap_or(0);
+    let step = cmp::max(1000, most + 1);
+    let cost = |i: usize| if points[i] > most { 1 } else { most + 2 - points[i] };
+
     let mut q: BinaryHeap<(i32, Unit)> = BinaryHeap::new();  // max-heap.
     q.push((0, source.clone()));
     let mut parents: HashMap<Unit, (Command, Unit)> = HashMap::new();
     let mut power: HashSet<Unit> = HashSet::new();
     let mut dist: HashMap<Unit, i32> = HashMap::new();
     dist.insert(source.clone(), 0);
+    // Cheapest ending found so far: by a locking command at the target,
+    // which is free as every route needs one, or by a phrase from some tip
+    // whose last command locks at the target.
+    let mut lock_cost: Option<i32> = None;
+    let mut by_phrase: Option<(i32, Unit, usize, usize)> = None;
     // XXX we use parent links instead of a separate hash set
     // for visited nodes.
     parents.insert(source.clone(), (ALL_COMMANDS[0], source.clone()));
     while let Some((d, tip)) = q.pop() {
         assert!(board.check_unit_position(&tip));
         assert!(parents.contains_key(&tip));
-        if tip == *target {
+        let used = table_bytes::<Unit, (Command, Unit)>(parents.len())
+            + table_bytes::<Unit, i32>(dist.len())
+            + table_bytes::<Unit, ()>(power.len())
+            + q.len() * size_of::<(i32, Unit)>();
+        if !budget.fits(used) {
+            return route_because_it_works(source, target, board, budget);
+        }
+        let d = -d;
+        let best = cmp::min(lock_cost.unwrap_or(i32::max_value()),
+                            by_phrase.as_ref().map_or(i32::max_value(), |e| e.0));
+        if d >= best {
             break
         } else if d > *dist.get(&tip).unwrap_or(&i32::max_value()) ||
             power.contains(&tip) {
             continue
         }
+        if tip == *target {
+            lock_cost = Some(d);
+        }
 
-        let d = -d;
-
-        'phrases: for phrase in phrases.iter() {
+        'phrases: for (i, phrase) in phrases.iter().enumerate() {
             let mut next = tip.clone();
-            for c in phrase.iter() {
-                next = next.apply(c);
-                if parents.contains_key(&next) || !board.check_unit_position(&next) {
+            for (k, c) in phrase.iter().enumerate() {
+                let moved = next.apply(c);
+                if next == *target && !board.check_unit_position(&moved) {
+                    let tail = &phrase[k + 1..];
+                    let fits = tail.is_empty() || spawned.as_ref().map_or(false, |s| {
+                        s.0.check_unit_position(&s.1) && moves_freely(&s.1, tail, &s.0)
+                    });
+                    if fits && d + cost(i) < by_phrase.as_ref().map_or(i32::max_value(), |e| e.0) {
+                        by_phrase = Some((d + cost(i), tip.clone(), i, k));
+                    }
+                    continue 'phrases
+                }
+                next = moved;
+                // The end may be one reached before, if this is cheaper.
+                let end = k + 1 == phrase.len();
+                if !free(&next) || (!end && parents.contains_key(&next)) {
                     continue 'phrases
                 }
             }
 
             power.insert(tip.clone());
-            let score = (max - phrase.len() + 1) as i32;
-            if d + score < *dist.get(&next).unwrap_or(&i32::max_value()) {
-                q.push((-(d + score), next.clone()));
-                dist.insert(next.clone(), d + score);
+            let d_next = d + cost(i);
+            if d_next < *dist.get(&next).unwrap_or(&i32::max_value()) {
+                q.push((-d_next, next.clone()));
                 let mut next = tip.clone();
                 for c in phrase {
                     let next_next = next.apply(c);
@@ -112,68 +275,87 @@ pub fn route(source: &Unit, target: &Unit, board: &Board,
                     parents.insert(next_next.clone(), (*c, next));
                     next = next_next;
                 }
+                // Only the end of the phrase is ever expanded.
+                dist.insert(next, d_next);
             }
         }
 
+        if tip == *target {
+            continue
+        }
         for c in ALL_COMMANDS.iter() {
             let next = tip.apply(c);
-            if board.check_unit_position(&next) {
-                if d + penalty < *dist.get(&next).unwrap_or(&i32::max_value()) {
-                    q.push((-(d + penalty), next.clone()));
-                    dist.insert(next.clone(), d + penalty);
+            if free(&next) {
+                if d + step < *dist.get(&next).unwrap_or(&i32::max_value()) {
+                    q.push((-(d + step), next.clone()));
+                    dist.insert(next.clone(), d + step);
                     parents.insert(next.clone(), (*c, tip.clone()));
                 }
             }
         }
     }
 
-    if !parents.contains_key(target) {
-        return None;  // no path found.
-    }
-
-    let mut seen: HashSet<Vec<(i32, i32)>> = HashSet::new();
-    seen.insert(xy(&target));
-
-    let mut path = Vec::new();
-    let mut tip = target;
-    while tip != source {
-        assert!(parents.contains_key(&tip));
-        let (c, ref next) = parents[tip];
-        path.push(c);
-        let xy = xy(&next);
-        if seen.contains(&xy) {
+    if let Some((total, tip, i, k)) = by_phrase {
+        if total < lock_cost.unwrap_or(i32::max_value()) {
             // got cycles? try again.
-            return route_because_it_works(source, target, board);
+            let (mut path, mut seen) = match path_to(&parents, source, &tip) {
+                Some(p) => p,
+                None => return route_because_it_works(source, target, board, budget)
+            };
+            let mut next = tip;
+            for c in phrases[i][..k].iter() {
+                next = next.apply(c);
+                if !seen.insert(next.placement()) {
+                    return route_because_it_works(source, target, board, budget);
+                }
+            }
+            path.extend(phrases[i].iter().cloned());
+            return Ok(Some(path))
         }
+    }
 
-        seen.insert(xy);
-        tip = next;
+    if !parents.contains_key(target) {
+        return Ok(None);  // no path found.
     }
-    path.reverse();
+
+    // got cycles? try again.
+    let (mut path, seen) = match path_to(&parents, source, target) {
+        Some(p) => p,
+        None => return route_because_it_works(source, target, board, budget)
+    };
 
     for c in ALL_COMMANDS.iter() {
         let locked = target.apply(c);
         if !board.check_unit_position(&locked) {
-            assert!(!seen.contains(&xy(&locked)));
+            assert!(!seen.contains(&locked.placement()));
             path.push(*c);
-            return Some(path)
+            return Ok(Some(path))
         }
     }
 
-    panic!("sai wat?");
+    Err(Error::Search("no command locks the unit at its target".to_string()))
 }
 
-fn reachable<'a>(source: &Unit<'a>, board: &Board) -> HashSet<Unit<'a>> {
+/// Units reachable from `source` without passing a `visited` placement.
+/// Stops exploring once the table outgrows `budget`, so the result may miss
+/// some far away units.
+fn reachable<'a>(source: &Unit<'a>, visited: &HashSet<Placement>, board: &Board,
+                 budget: &Budget) -> HashSet<Unit<'a>> {
     let mut q = VecDeque::new();
     q.push_back(source.clone());
     let mut seen: HashSet<Unit<'a>> = HashSet::new();
     seen.insert(source.clone());
     while let Some(tip) = q.pop_front() {
         assert!(board.check_unit_position(&tip));
+        let used = table_bytes::<Unit, ()>(seen.len()) + q.len() * size_of::<Unit>();
+        if !budget.fits(used) {
+            break
+        }
 
         for cj in ALL_COMMANDS.iter() {
             let next = tip.apply(cj);
-            if !seen.contains(&next) && board.check_unit_position(&next) {
+            if !seen.contains(&next) && board.check_unit_position(&next)
+                && !visited.contains(&next.placement()) {
                 q.push_back(next.clone());
                 seen.insert(next);
             }
@@ -195,9 +377,16 @@ fn can_be_locked<'a>(unit: &Unit<'a>, board: &Board) -> bool {
 }
 
 
-pub fn candidates<'a>(unit: &Unit<'a>, board: &Board) -> Vec<Unit<'a>>{
+pub fn candidates<'a>(unit: &Unit<'a>, board: &Board, budget: &Budget) -> Vec<Unit<'a>>{
+    candidates_avoiding(unit, &HashSet::new(), board, budget)
+}
+
+/// `candidates` the unit can get to without passing a `visited` placement.
+pub fn candidates_avoiding<'a>(unit: &Unit<'a>, visited: &HashSet<Placement>, board: &Board,
+                               budget: &Budget) -> Vec<Unit<'a>> {
     let mut result = Vec::new();
-    let r = reachable(unit, board);
+    let r = reachable(unit, visited, board, budget);
+    let mut seen = HashSet::new();
     let rots = [
         Command::Rotate(Angle::Left),
         Command::Rotate(Angle::Right)];
@@ -213,7 +402,7 @@ pub fn candidates<'a>(unit: &Unit<'a>, board: &Board) -> Vec<Unit<'a>>{
             }
             for moved in candidates {
                 if board.check_unit_position(&moved) && r.contains(&moved)
-                    && can_be_locked(&moved, board) {
+                    && can_be_locked(&moved, board) && seen.insert(moved.placement()) {
                     result.push(moved);
                 }
             }
@@ -222,70 +411,713 @@ pub fn candidates<'a>(unit: &Unit<'a>, board: &Board) -> Vec<Unit<'a>>{
     result
 }
 
-pub fn best_position<'a>(unit: &Unit<'a>, next_unit: &Option<Unit<'a>>,
-                         board: &Board) -> Vec<Unit<'a>> {
-    let mut result = Vec::new();
-    for moved in candidates(unit, board) {
-        let board_with_moved = board.lock_unit(&moved).0;
-        let score = match next_unit {
-            &None => scoring_function(&board_with_moved),
-            &Some(ref next) => {
-                if board_with_moved.check_unit_position(&next) {
-                    candidates(next, &board_with_moved).iter()
-                        .map(|c| scoring_function(&board_with_moved.lock_unit(&c).0))
-                        .max().unwrap_or(0)
-                } else {
-                     scoring_function(&board_with_moved)
-                }
+/// How far `best_position` looks ahead: `depth` units counting the
+/// current one, keeping the `width` best boards after each further unit.
+#[derive(Clone, Copy, Debug)]
+pub struct Search {
+    pub depth: usize,
+    pub width: usize
+}
+
+impl Default for Search {
+    fn default() -> Search {
+        Search { depth: 2, width: 16 }
+    }
+}
+
+/// A board reached by placing the current unit at candidate `origin` and
+/// some of the following units after it.
+#[derive(Clone)]
+struct Branch {
+    origin: usize,
+    board: Board,
+    cleared: i32,
+    score: i64
+}
 
+/// Keeps the `width` best of `branches`.
+fn prune(branches: &mut Vec<Branch>, width: usize) {
+    branches.sort_by(|a, b| b.score.cmp(&a.score));
+    branches.truncate(width);
+}
+
+/// Candidates for `unit`, best first as rated by `eval`. A beam search over
+/// the `upcoming` shapes rates every candidate by the best board reachable
+/// from it within `search.depth` units; candidates that fall out of the
+/// beam come after the rest, rated on their own. Once `budget` expires the
+/// deepest completed level is used.
+pub fn best_position<'a>(unit: &Unit<'a>, upcoming: &[&'a Shape],
+                         board: &Board, eval: &Evaluator,
+                         search: &Search, budget: &Budget) -> Vec<Unit<'a>> {
+    let moves = candidates(unit, board, budget);
+    let mut beam: Vec<Branch> = moves.iter().enumerate().map(|(i, moved)| {
+        let (board, cleared) = board.lock_unit(moved);
+        let score = eval.evaluate(&board, cleared);
+        Branch { origin: i, board: board, cleared: cleared, score: score }
+    }).collect();
+    let own: Vec<i64> = beam.iter().map(|b| b.score).collect();
+
+    // A level is pruned as it grows, so it never holds much more than
+    // twice the width.
+    let branch_bytes = size_of::<Branch>() + board.bytes();
+    let width = (1..search.width + 1).rev()
+        .find(|&w| budget.fits(2 * w * branch_bytes))
+        .unwrap_or(1);
+    for shape in upcoming.iter().take(search.depth.saturating_sub(1)) {
+        let mut level = Vec::new();
+        for branch in beam.iter() {
+            if budget.expired() {
+                break
+            }
+            let next = branch.board.place_new_unit(shape);
+            if !branch.board.check_unit_position(&next) {
+                // The game would end here.
+                level.push(branch.clone());
+                continue
             }
+            for moved in candidates(&next, &branch.board, budget) {
+                let (board, cleared) = branch.board.lock_unit(&moved);
+                let cleared = branch.cleared + cleared;
+                let score = eval.evaluate(&board, cleared);
+                level.push(Branch {
+                    origin: branch.origin, board: board, cleared: cleared, score: score
+                });
+            }
+            if level.len() >= 2 * width {
+                prune(&mut level, width);
+            }
+        }
+        if budget.expired() {
+            break
+        }
+        prune(&mut level, width);
+        beam = level;
+    }
+
+    let mut deep: Vec<Option<i64>> = vec![None; moves.len()];
+    for branch in beam.iter() {
+        deep[branch.origin] = cmp::max(deep[branch.origin], Some(branch.score));
+    }
+    let mut result: Vec<_> = moves.into_iter().enumerate()
+        .map(|(i, u)| (deep[i], own[i], u))
+        .collect();
+    result.sort_by(|&(d1, s1, _), &(d2, s2, _)| (d2, s2).cmp(&(d1, s1)));
+    result.into_iter().map(|(_, _, u)| u).collect()
+}
+
+
+/// Settings of the Monte Carlo tree search: `playouts` per unit placed,
+/// the `width` best placements kept as children of every node, and
+/// `rollout` units placed greedily after a leaf.
+#[derive(Clone, Copy, Debug)]
+pub struct Mcts {
+    pub playouts: usize,
+    pub width: usize,
+    pub rollout: usize
+}
+
+impl Default for Mcts {
+    fn default() -> Mcts {
+        Mcts { playouts: 64, width: 8, rollout: 4 }
+    }
+}
+
+/// A board reached by locking `unit`, and the unit `source[next]` of the
+/// game to be placed on it.
+struct Node<'a> {
+    unit: Option<Unit<'a>>,
+    board: Board,
+    next: usize,
+    cleared: i32,
+    /// Move score of the lock that led here.
+    reward: i32,
+    /// `None` until expanded, empty if the game ends here.
+    children: Option<Vec<usize>>,
+    visits: u32,
+    value: f64
+}
+
+/// `source[next]` spawned on `board`; `None` if the source is exhausted or
+/// the unit does not fit.
+fn spawn<'a>(g: &'a Game, board: &Board, next: usize) -> Option<Unit<'a>> {
+    let unit = match g.source.get(next) {
+        Some(&i) => board.place_new_unit(&g.shapes[i]),
+        None => return None
+    };
+    if board.check_unit_position(&unit) { Some(unit) } else { None }
+}
+
+/// Lockable placements `unit` can get to on `board` without passing a
+/// `visited` one, best first as rated by `eval`, each with the board it
+/// leaves and the lines it clears.
+fn ranked_moves<'a>(unit: &Unit<'a>, visited: &HashSet<Placement>, board: &Board,
+                    eval: &Evaluator, budget: &Budget) -> Vec<(Unit<'a>, Board, i32)> {
+    let candidates = candidates_avoiding(unit, visited, board, budget);
+    let mut moves: Vec<_> = candidates.into_iter().map(|u| {
+        let (board, cleared) = board.lock_unit(&u);
+        let score = eval.evaluate(&board, cleared);
+        (score, u, board, cleared)
+    }).collect();
+    moves.sort_by(|a, b| b.0.cmp(&a.0));
+    moves.into_iter().map(|(_, u, b, c)| (u, b, c)).collect()
+}
+
+/// Move score of placing up to `n` units greedily, starting with
+/// `source[next]` on `board`.
+fn rollout(g: &Game, board: &Board, next: usize, cleared_prev: i32, n: usize,
+           eval: &Evaluator, budget: &Budget) -> i32 {
+    let mut board = board.clone();
+    let mut cleared_prev = cleared_prev;
+    let mut total = 0;
+    for next in next..next + n {
+        let best = spawn(g, &board, next).and_then(|unit| {
+            ranked_moves(&unit, &HashSet::new(), &board, eval, budget).into_iter().next()
+        });
+        let (unit, new_board, cleared) = match best {
+            Some(m) => m,
+            None => break
         };
-        result.push((moved, score));
+        total += move_score(unit.size(), cleared, cleared_prev);
+        board = new_board;
+        cleared_prev = cleared;
+    }
+    total
+}
+
+/// Creates the children of `nodes[id]` out of the first `width` of `moves`.
+fn expand<'a>(nodes: &mut Vec<Node<'a>>, id: usize, moves: Vec<(Unit<'a>, Board, i32)>,
+              width: usize) {
+    let (next, cleared_prev) = (nodes[id].next, nodes[id].cleared);
+    let mut children = Vec::new();
+    for (unit, board, cleared) in moves.into_iter().take(width) {
+        children.push(nodes.len());
+        nodes.push(Node {
+            reward: move_score(unit.size(), cleared, cleared_prev),
+            unit: Some(unit),
+            board: board,
+            next: next + 1,
+            cleared: cleared,
+            children: None,
+            visits: 0,
+            value: 0.0
+        });
+    }
+    nodes[id].children = Some(children);
+}
+
+/// Child of `nodes[id]` to descend into: an unvisited one if any, else the
+/// one with the best UCB1 bound, values being scaled by `scale`.
+fn select(nodes: &Vec<Node>, id: usize, scale: f64) -> Option<usize> {
+    let children = nodes[id].children.as_ref().unwrap();
+    let ln_n = (nodes[id].visits as f64).ln();
+    let ucb = |c: usize| {
+        let n = nodes[c].visits as f64;
+        nodes[c].value / n / scale + (2.0 * ln_n / n).sqrt()
+    };
+    match children.iter().find(|&&c| nodes[c].visits == 0) {
+        Some(&c) => Some(c),
+        None => children.iter().cloned()
+            .max_by(|&a, &b| ucb(a).partial_cmp(&ucb(b)).unwrap())
+    }
+}
+
+/// Candidates for `unit`, the unit `source[next]` of the game, on `board`,
+/// best first, as ranked by a Monte Carlo tree search over the following
+/// units. Placements in `visited` are neither targets nor passed on the
+/// way to one. Every playout
+/// descends the tree, grows it by one node and finishes with a greedy
+/// rollout; its value is the move score gained. Runs `mcts.playouts`
+/// playouts, fewer if `budget` expires first; candidates the tree did not
+/// keep follow in the order `eval` rates them.
+pub fn mcts_position<'a>(g: &'a Game, unit: &Unit<'a>, visited: &HashSet<Placement>,
+                         board: &Board, next: usize, cleared_prev: i32,
+                         eval: &Evaluator, mcts: &Mcts,
+                         budget: &Budget) -> Vec<Unit<'a>> {
+    let mut nodes = vec![Node {
+        unit: None,
+        board: board.clone(),
+        next: next,
+        cleared: cleared_prev,
+        reward: 0,
+        children: None,
+        visits: 0,
+        value: 0.0
+    }];
+    let moves = ranked_moves(unit, visited, board, eval, budget);
+    let rest: Vec<Unit<'a>> = moves.iter().skip(mcts.width).map(|m| m.0.clone()).collect();
+    expand(&mut nodes, 0, moves, mcts.width);
+
+    let node_bytes = size_of::<Node>() + board.bytes();
+    let mut scale = 1.0;
+    for _ in 0..mcts.playouts {
+        if budget.expired() {
+            break
+        }
+        let mut path = vec![0];
+        let mut id = 0;
+        let mut gained = 0;
+        loop {
+            let leaf = nodes[id].children.is_none();
+            if leaf {
+                if !budget.fits((nodes.len() + mcts.width) * node_bytes) {
+                    break
+                }
+                let board = &nodes[id].board;
+                let moves = match spawn(g, board, nodes[id].next) {
+                    Some(unit) => ranked_moves(&unit, &HashSet::new(), board, eval, budget),
+                    None => Vec::new()
+                };
+                expand(&mut nodes, id, moves, mcts.width);
+            }
+            match select(&nodes, id, scale) {
+                Some(c) => {
+                    id = c;
+                    gained += nodes[id].reward;
+                    path.push(id);
+                }
+                None => break
+            }
+            if leaf {
+                break
+            }
+        }
+        gained += rollout(g, &nodes[id].board, nodes[id].next, nodes[id].cleared,
+                          mcts.rollout, eval, budget);
+        scale = f64::max(scale, gained as f64);
+        for &id in path.iter() {
+            nodes[id].visits += 1;
+            nodes[id].value += gained as f64;
+        }
     }
-    result.sort_by(|&(_, s1), &(_, s2)| s2.cmp(&s1));
-    result.into_iter().map(|(u, _)| u).collect()
+
+    let mut children: Vec<usize> = nodes[0].children.clone().unwrap();
+    children.sort_by(|&a, &b| {
+        (nodes[b].visits, nodes[b].value).partial_cmp(&(nodes[a].visits, nodes[a].value))
+            .unwrap()
+    });
+    children.into_iter()
+        .map(|c| nodes[c].unit.clone().unwrap())
+        .chain(rest)
+        .collect()
+}
+
+/// Commands issued in a game, every position it went through, and the
+/// outcome that ended it, `None` if it was cut short.
+pub type Played<'a> = (Vec<Command>, Vec<GamePosition<'a>>, Option<StepOutcome<'a>>);
+
+/// Plays the game to the end, or until `budget` runs out. Returns the
+/// commands issued, every position the game went through, and the outcome
+/// that ended it (`None` if the budget ran out first). Every placement is
+/// chosen with `search`.
+pub fn play<'a>(g: &'a Game, phrases: &Vec<Vec<Command>>, search: &Search,
+                eval: &Evaluator, budget: &Budget)
+                -> Result<Played<'a>> {
+    play_with(g, phrases, budget, |position| {
+        let upcoming = position.upcoming_shapes(search.depth.saturating_sub(1));
+        best_position(&position.unit, &upcoming, &position.board, eval, search, budget)
+    })
 }
 
-pub fn scoring_function(board: &Board) -> i64 {
-    let penalty: Vec<_> = (0..board.height as i64).rev().map(|i| -i).collect();
-    let full_row_cost = 10000;
-    // let hole_penalty = 0;
-    return (board.n_full_rows() * full_row_cost) as i64 + board.total_sum(&penalty);
-        // + (board.n_holes() as i64) * hole_penalty;
+/// Like `play`, with every placement chosen by `mcts_position`. Each unit
+/// gets an equal share of the time left.
+pub fn play_mcts<'a>(g: &'a Game, phrases: &Vec<Vec<Command>>, mcts: &Mcts,
+                     eval: &Evaluator, budget: &Budget)
+                     -> Result<Played<'a>> {
+    play_with(g, phrases, budget, |position| {
+        let units_left = g.source.len() + 1 - position.next_source;
+        let share = budget.share(units_left as u32);
+        mcts_position(g, &position.unit, &position.visited, &position.board,
+                      position.unit_index(), position.cleared_lines_prev,
+                      eval, mcts, &share)
+    })
 }
 
+/// Writes a warning about the game of `g` to stderr.
+fn warn(g: &Game, message: &str) {
+    writeln!(&mut io::stderr(), "seed {}: {}", g.seed, message).unwrap();
+}
 
-pub fn play<'a>(g: &'a Game, phrases: &Vec<Vec<Command>>) -> (Vec<Command>, Vec<GamePosition<'a>>) {
-    let mut cur_game_pos = GamePosition::start(g);
+/// Plays `commands` from `position`: the commands applied, the positions
+/// they go through and the outcome that ended the game, if one did.
+/// `None` if they revisit a placement. The commands are spelled as `encode`
+/// spells them, so the positions count the phrases of power they place.
+fn follow<'a>(position: &GamePosition<'a>, commands: Vec<Command>) -> Option<Played<'a>> {
+    let symbols: Vec<char> = encode(&commands, &position.game.phrases).chars().collect();
+    let mut position = position.clone();
+    let mut positions = Vec::new();
+    for i in 0..commands.len() {
+        match position.step_symbol(symbols[i]).unwrap() {
+            StepOutcome::Moved(p) | StepOutcome::Locked(p) => {
+                position = p;
+                positions.push(position.clone())
+            }
+            StepOutcome::IllegalRepeat(_) => return None,
+            outcome => {
+                positions.push(outcome.position().clone());
+                return Some((commands[..i + 1].to_vec(), positions, Some(outcome)))
+            }
+        }
+    }
+    Some((commands, positions, None))
+}
+
+/// `follow` of the route to `target`; `None` if there is no usable one.
+fn play_route<'a>(position: &GamePosition<'a>, target: &Unit<'a>,
+                  phrases: &Vec<Vec<Command>>, budget: &Budget) -> Option<Played<'a>> {
+    match route(position, target, phrases, budget) {
+        Ok(Some(commands)) => follow(position, commands),
+        Ok(None) => None,
+        Err(e) => {
+            warn(position.game, &e.to_string());
+            None
+        }
+    }
+}
+
+/// Fewest commands that lock the unit of `position` without revisiting a
+/// placement.
+fn shortest_lock(position: &GamePosition) -> Option<Vec<Command>> {
+    let board = &position.board;
+    let mut q = VecDeque::new();
+    q.push_back(position.unit.clone());
+    let mut parents: HashMap<Unit, (Command, Unit)> = HashMap::new();
+    let mut seen = position.visited.clone();
+    while let Some(tip) = q.pop_front() {
+        if let Some(&c) = ALL_COMMANDS.iter().find(|c| !board.check_unit_position(&tip.apply(c))) {
+            let mut path = vec![c];
+            let mut unit = &tip;
+            while let Some(&(c, ref parent)) = parents.get(unit) {
+                path.push(c);
+                unit = parent;
+            }
+            path.reverse();
+            return Some(path)
+        }
+
+        for c in ALL_COMMANDS.iter() {
+            let next = tip.apply(c);
+            if board.check_unit_position(&next) && seen.insert(next.placement()) {
+                parents.insert(next.clone(), (*c, tip.clone()));
+                q.push_back(next);
+            }
+        }
+    }
+    None
+}
+
+/// Plays the game, locking every unit at the first of the targets `rank`
+/// returns for the position that can be routed to. Failing that the unit
+/// goes to any other lockable target, then takes the shortest way to lock;
+/// if even that fails the game ends there with a warning.
+fn play_with<'a, F>(g: &'a Game, phrases: &Vec<Vec<Command>>, budget: &Budget, mut rank: F)
+                    -> Result<Played<'a>>
+    where F: FnMut(&GamePosition<'a>) -> Vec<Unit<'a>>
+{
+    let start = GamePosition::start(g);
+    if start.is_spawn_blocked() {
+        return Ok((Vec::new(), vec![start.clone()], Some(StepOutcome::SpawnBlocked(start))))
+    }
+
+    let mut cur_game_pos = start;
     let mut commands: Vec<Command> = Vec::new();
     let mut positions: Vec<GamePosition> = vec![cur_game_pos.clone()];
-    let mut i = 0;
-    'outer: while cur_game_pos.board.check_unit_position(&cur_game_pos.unit) {
-        i += 1;
-        // let mut stderr = io::stderr();
-        // writeln!(&mut stderr, "{} out of {}", i, g.source.len()).unwrap();
-        let best_positions = best_position(&cur_game_pos.unit,
-                                           &cur_game_pos.next_unit(),
-                                           &cur_game_pos.board);
-        let mut moved = false;
-        for target in best_positions {
-            if let Some(new_commands) = route(&cur_game_pos.unit, &target,
-                                              &cur_game_pos.board, phrases) {
-                for &cmd in new_commands.iter() {
-                    if let Some(new_pos) = cur_game_pos.step(cmd) {
-                        cur_game_pos = new_pos;
-                        positions.push(cur_game_pos.clone())
-                    } else {
-                        break 'outer;
-                    }
-                }
+    while !budget.expired() {
+        let ranked = rank(&cur_game_pos);
+        let mut played = ranked.iter()
+            .filter_map(|target| play_route(&cur_game_pos, target, phrases, budget))
+            .next();
+        if played.is_none() {
+            let others = candidates_avoiding(&cur_game_pos.unit, &cur_game_pos.visited,
+                                             &cur_game_pos.board, budget);
+            played = others.iter()
+                .filter(|&target| !ranked.contains(target))
+                .filter_map(|target| play_route(&cur_game_pos, target, phrases, budget))
+                .next();
+        }
+        if played.is_none() {
+            played = shortest_lock(&cur_game_pos)
+                .and_then(|path| follow(&cur_game_pos, path));
+        }
+
+        match played {
+            Some((new_commands, new_positions, end)) => {
+                cur_game_pos = new_positions.last().unwrap().clone();
+                positions.extend(new_positions);
                 commands.extend(new_commands);
-                moved = true;
-                break;
+                if end.is_some() {
+                    return Ok((commands, positions, end))
+                }
             }
+            None => {
+                // The game so far is still a valid solution.
+                warn(g, &format!("unit {} cannot be locked, ending the game",
+                                 cur_game_pos.unit_index()));
+                break
+            }
+        }
+    }
+
+    Ok((commands, positions, None))
+}
+
+/// Drops every unit straight down, zigzagging SW and SE. Each move goes one
+/// row down, so no placement repeats; this is the instant fallback of
+/// `solve`.
+pub fn drop_all(g: &Game) -> Vec<Command> {
+    let zigzag = [Command::Move(Direction::ZX), Command::Move(Direction::ZY)];
+    let mut position = GamePosition::start(g);
+    let mut commands = Vec::new();
+    if position.is_spawn_blocked() {
+        return commands
+    }
+    loop {
+        let c = zigzag[commands.len() % 2];
+        commands.push(c);
+        let outcome = position.step(c);
+        if outcome.is_game_over() {
+            return commands
+        }
+        position = outcome.into_position();
+    }
+}
+
+/// A way of playing a game: turns it and the phrases of power into
+/// commands within `budget`.
+pub trait Strategy: Sync {
+    fn solve(&self, g: &Game, phrases: &Vec<String>, budget: &Budget) -> Result<Vec<Command>>;
+}
+
+/// Names accepted by `by_name`, the default first.
+pub const STRATEGIES: [&'static str; 3] = ["greedy", "mcts", "drop"];
+
+/// The strategy called `name`, rating boards with `eval`.
+pub fn by_name<'e>(name: &str, eval: &'e Evaluator, search: Search,
+                   mcts: Mcts) -> Option<Box<Strategy + 'e>> {
+    match name {
+        "greedy" => Some(Box::new(Greedy { eval: eval, search: search })),
+        "mcts" => Some(Box::new(MonteCarlo { eval: eval, mcts: mcts })),
+        "drop" => Some(Box::new(DropAll)),
+        _ => None
+    }
+}
+
+/// Places every unit with `best_position` and routes it there.
+pub struct Greedy<'e> {
+    pub eval: &'e Evaluator,
+    pub search: Search
+}
+
+/// Places every unit with `mcts_position` and routes it there.
+pub struct MonteCarlo<'e> {
+    pub eval: &'e Evaluator,
+    pub mcts: Mcts
+}
+
+/// `drop_all`, as a baseline.
+pub struct DropAll;
+
+/// Runs `solvers` in turn while `budget` allows, starting from `drop_all`
+/// so there always is a complete solution, and keeps the best scoring one.
+fn anytime<F>(g: &Game, phrases: &Vec<String>, budget: &Budget,
+              solvers: Vec<F>) -> Result<Vec<Command>>
+    where F: Fn() -> Result<Vec<Command>>
+{
+    let mut best = drop_all(g);
+    let mut best_score = replay::score(g, &encode(&best, phrases));
+    for solver in solvers {
+        if budget.expired() {
+            break
+        }
+        // An unfinished game is still a valid solution.
+        let commands = try!(solver());
+        let score = replay::score(g, &encode(&commands, phrases));
+        if score > best_score {
+            best = commands;
+            best_score = score;
+        }
+    }
+    Ok(best)
+}
+
+impl<'e> Strategy for Greedy<'e> {
+    /// With a time limit, searches of growing depth up to `search.depth`
+    /// are run while there is time; without one only the deepest is.
+    fn solve(&self, g: &Game, phrases: &Vec<String>, budget: &Budget) -> Result<Vec<Command>> {
+        let power = phrases.iter().map(phrase_to_commands).collect();
+        if !budget.is_limited() {
+            return play(g, &power, &self.search, self.eval, budget).map(|p| p.0)
         }
-        assert!(moved);
+        let solvers = (1..self.search.depth + 1).map(|depth| {
+            let search = Search { depth: depth, ..self.search };
+            let power = &power;
+            move || play(g, power, &search, self.eval, budget).map(|p| p.0)
+        }).collect();
+        anytime(g, phrases, budget, solvers)
     }
+}
+
+impl<'e> Strategy for MonteCarlo<'e> {
+    fn solve(&self, g: &Game, phrases: &Vec<String>, budget: &Budget) -> Result<Vec<Command>> {
+        let power = phrases.iter().map(phrase_to_commands).collect();
+        if !budget.is_limited() {
+            return play_mcts(g, &power, &self.mcts, self.eval, budget).map(|p| p.0)
+        }
+        let power = &power;
+        anytime(g, phrases, budget,
+                vec![|| play_mcts(g, power, &self.mcts, self.eval, budget).map(|p| p.0)])
+    }
+}
+
+impl Strategy for DropAll {
+    fn solve(&self, g: &Game, _: &Vec<String>, _: &Budget) -> Result<Vec<Command>> {
+        Ok(drop_all(g))
+    }
+}
+
+#[test]
+fn phrase_tails_need_the_next_unit_to_spawn() {
+    use encoder::command_to_symbol;
+
+    let east = Command::Move(Direction::XY);
+    let west = Command::Move(Direction::YX);
+    let south_west = Command::Move(Direction::ZX);
+    // Locking the pair one cell east covers the spawn cell of the dot.
+    let pair = vec![(0, 0), (1, 0)].iter()
+        .map(|c| offset_to_cube(c) - offset_to_cube(&(0, 0)))
+        .collect();
+    let phrase = vec![east, south_west, west];
+    let game = Game {
+        board: Board::new(3, 1, vec![].into_iter()).unwrap(),
+        shapes: vec![Shape::new(0, &pair), Shape::new(1, &vec![offset_to_cube(&(0, 0))])],
+        source: vec![0, 1],
+        seed: 0,
+        phrases: vec![phrase.iter().map(command_to_symbol).collect()]
+    };
+    let start = GamePosition::start(&game);
+    let target = start.unit.apply(&east);
+    let path = route(&start, &target, &vec![phrase], &Budget::unlimited()).unwrap().unwrap();
+    assert!(path.len() == 2 && path[0] == east);
+}
+
+#[test]
+fn phrase_tails_move_the_next_unit() {
+    use encoder::command_to_symbol;
+
+    let east = Command::Move(Direction::XY);
+    let west = Command::Move(Direction::YX);
+    let south_west = Command::Move(Direction::ZX);
+    // Locks a dot one cell east of the spawn point, then moves the next
+    // dot one cell west of it.
+    let phrase = vec![east, south_west, west];
+    let game = dots(5, 1, &[], 2, vec![phrase.iter().map(command_to_symbol).collect()]);
+    let start = GamePosition::start(&game);
+    let target = start.unit.apply(&east);
+    let path = route(&start, &target, &vec![phrase.clone()], &Budget::unlimited())
+        .unwrap().unwrap();
+    assert!(path == phrase);
+    let (commands, positions, end) = follow(&start, path).unwrap();
+    assert!(commands == phrase && end.is_none());
+    let next = positions.last().unwrap();
+    assert!(next.next_source == 2 && next.power_counts == vec![1]);
+    let spawned = next.board.place_new_unit(&game.shapes[0]);
+    assert!(next.unit == spawned.apply(&west));
+    assert!(next.visited.contains(&spawned.placement()));
+    assert!(next.visited.contains(&next.unit.placement()));
+    // The game goes on from the moved unit.
+    let (_, _, end) = follow(next, shortest_lock(next).unwrap()).unwrap();
+    assert!(match end { Some(StepOutcome::SourceExhausted(_)) => true, _ => false });
+}
+
+#[test]
+fn used_phrases_lose_their_bonus() {
+    use encoder::command_to_symbol;
+
+    let south_east = Command::Move(Direction::ZY);
+    let south_west = Command::Move(Direction::ZX);
+    // Two ways down to the same placement.
+    let phrases = vec![vec![south_west, south_east], vec![south_east, south_west]];
+    let symbols = phrases.iter().map(|p| p.iter().map(command_to_symbol).collect()).collect();
+    let game = dots(5, 3, &[], 1, symbols);
+    let start = GamePosition::start(&game);
+    let target = start.unit.apply(&south_west).apply(&south_east);
+    for used in 0..2 {
+        let (_, positions, _) = follow(&start, phrases[used].clone()).unwrap();
+        let mut position = start.clone();
+        position.power_counts = positions.last().unwrap().power_counts.clone();
+        assert!(position.power_counts[used] == 1);
+        let path = route(&position, &target, &phrases, &Budget::unlimited()).unwrap().unwrap();
+        assert!(path[..2] == phrases[1 - used][..]);
+    }
+}
+
+/// Rates boards by the lines cleared, then by the cells filled in the top
+/// row.
+#[cfg(test)]
+struct TopHeavy;
+
+#[cfg(test)]
+impl Evaluator for TopHeavy {
+    fn evaluate(&self, board: &Board, cleared_lines: i32) -> i64 {
+        let top = (0..board.width as i32).filter(|&x| !board.is_free(x, 0)).count();
+        cleared_lines as i64 * 100 + top as i64
+    }
+}
+
+#[test]
+fn the_beam_sees_a_line_clear_two_units_ahead() {
+    let dot = Shape::new(0, &vec![offset_to_cube(&(0, 0))]);
+    // Two dots complete the bottom row, but only if the first goes there.
+    let board = Board::new(3, 2, vec![(0, 1)].into_iter()).unwrap();
+    let unit = board.place_new_unit(&dot);
+    let search = |depth| Search { depth: depth, width: 16 };
+    let best = best_position(&unit, &[&dot], &board, &TopHeavy, &search(1),
+                             &Budget::unlimited());
+    assert!(best[0].pivot().1 == 0);
+    let best = best_position(&unit, &[&dot], &board, &TopHeavy, &search(2),
+                             &Budget::unlimited());
+    assert!(best[0].pivot().1 == 1);
+}
+
+#[test]
+fn mcts_ranks_every_candidate() {
+    let game = dots(4, 4, &[(0, 3), (3, 3)], 3, vec![]);
+    let budget = Budget::unlimited();
+    let unit = game.board.place_new_unit(&game.shapes[0]);
+    let mcts = Mcts { playouts: 8, width: 2, rollout: 2 };
+    let ranked = mcts_position(&game, &unit, &HashSet::new(), &game.board, 0, 0,
+                               &TopHeavy, &mcts, &budget);
+    let mut ranked: Vec<Placement> = ranked.iter().map(|u| u.placement()).collect();
+    let mut all: Vec<Placement> = candidates(&unit, &game.board, &budget).iter()
+        .map(|u| u.placement())
+        .collect();
+    assert!(all.len() > mcts.width);
+    ranked.sort();
+    all.sort();
+    assert!(ranked == all);
+
+    // A unit moved off its spawn point by a phrase tail starts from there
+    // and keeps away from where it has been.
+    let moved = unit.apply(&Command::Move(Direction::ZY));
+    let below = candidates(&moved, &game.board, &budget);
+    assert!(below.iter().all(|u| u.pivot().1 > 0));
+    let visited: HashSet<Placement> = vec![moved.placement(), below[0].placement()]
+        .into_iter().collect();
+    let ranked = mcts_position(&game, &moved, &visited, &game.board, 0, 0,
+                               &TopHeavy, &mcts, &budget);
+    assert!(ranked.len() + 1 == below.len());
+    assert!(ranked.iter().all(|u| u.pivot().1 > 0 && u.placement() != below[0].placement()));
+}
 
-    return (commands, positions)
+#[test]
+fn the_shortest_lock_avoids_visited_placements() {
+    let game = dots(5, 5, &[], 2, vec![]);
+    let mut position = GamePosition::start(&game);
+    // Leaving through the west border would pass a visited placement.
+    let west = position.unit.apply(&Command::Move(Direction::YX)).placement();
+    position.visited.insert(west);
+    let path = shortest_lock(&position).unwrap();
+    assert!(path.len() == 3);
+    let (_, positions, end) = follow(&position, path).unwrap();
+    assert!(end.is_none() && positions.last().unwrap().next_source == 2);
+    assert!(positions.iter().all(|p| p.next_source == 2 || p.unit.placement() != west));
 }
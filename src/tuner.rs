@@ -0,0 +1,108 @@
+use rand::{SeedableRng, XorShiftRng};
+use rand::distributions::{IndependentSample, Normal};
+use simple_parallel;
+
+use encoder::{encode, phrase_to_commands};
+use error::{Error, Result};
+use evaluator::Weights;
+use game::Game;
+use replay;
+use strategy::{self, Budget, Search};
+
+/// Settings of a tuning run.
+pub struct Config {
+    pub phrases: Vec<String>,
+    /// Search the solver is run with, see `strategy::play`.
+    pub search: Search,
+    pub generations: usize,
+    /// Weight vectors tried per generation.
+    pub population: usize,
+    pub rng_seed: u32,
+    pub cores: usize
+}
+
+/// Average official score of the solver on `games` with `weights`.
+pub fn fitness(games: &[Game], weights: &Weights, config: &Config) -> Result<f64> {
+    let power = config.phrases.iter().map(phrase_to_commands).collect();
+    let mut total = 0;
+    for g in games {
+        let (commands, _, _) = try!(strategy::play(g, &power, &config.search,
+                                                   weights, &Budget::unlimited()));
+        total += replay::score(g, &encode(&commands, &config.phrases));
+    }
+    Ok(total as f64 / games.len() as f64)
+}
+
+/// Tunes the weights with a simple evolution strategy: every generation
+/// samples `population` weight vectors around the current mean, moves the
+/// mean to the average of the best quarter and widens or narrows the
+/// sampling depending on whether that beat the best score so far. The run
+/// is deterministic for a given `rng_seed`.
+///
+/// `report` is called after every generation with the best weights found
+/// so far and their score.
+pub fn tune<F>(games: &[Game], start: &Weights, config: &Config,
+               mut report: F) -> Result<Weights>
+    where F: FnMut(usize, &Weights, f64) -> Result<()>
+{
+    if config.population == 0 {
+        return Err(Error::Usage("the population needs at least one member".to_string()))
+    }
+    if config.cores == 0 {
+        return Err(Error::Usage("at least one core is needed".to_string()))
+    }
+    let mut rng: XorShiftRng = SeedableRng::from_seed(
+        [config.rng_seed, 0x193a6754, 0xa8a7d469, 0x97830e05]);
+    let normal = Normal::new(0.0, 1.0);
+    let mut pool = simple_parallel::Pool::new(config.cores);
+
+    let mut mean = start.to_vec();
+    let mut sigma: Vec<f64> = mean.iter().map(|w| w.abs().max(1.0) / 2.0).collect();
+    let mut best = start.clone();
+    let mut best_score = try!(fitness(games, &best, config));
+    try!(report(0, &best, best_score));
+
+    let n_parents = ::std::cmp::max(1, config.population / 4);
+    for generation in 1..config.generations + 1 {
+        let offspring: Vec<Weights> = (0..config.population).map(|_| {
+            let w: Vec<f64> = mean.iter().zip(sigma.iter())
+                .map(|(m, s)| m + s * normal.ind_sample(&mut rng))
+                .collect();
+            Weights::from_slice(&w)
+        }).collect();
+        let scores: Vec<f64> = try!(pool.map(offspring.iter(), &|w| fitness(games, w, config))
+            .collect());
+
+        let mut ranked: Vec<(f64, &Weights)> = scores.into_iter().zip(offspring.iter()).collect();
+        ranked.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap());
+        for (i, m) in mean.iter_mut().enumerate() {
+            *m = ranked[..n_parents].iter().map(|&(_, w)| w.to_vec()[i])
+                .fold(0.0, |a, b| a + b) / n_parents as f64;
+        }
+
+        let improved = ranked[0].0 > best_score;
+        for s in sigma.iter_mut() {
+            *s *= if improved { 1.2 } else { 0.85 };
+        }
+        if improved {
+            best = ranked[0].1.clone();
+            best_score = ranked[0].0;
+        }
+        try!(report(generation, &best, best_score));
+    }
+    Ok(best)
+}
+
+#[test]
+fn an_empty_population_is_rejected() {
+    let config = Config {
+        phrases: vec![],
+        search: Search::default(),
+        generations: 1,
+        population: 0,
+        rng_seed: 42,
+        cores: 1
+    };
+    let tuned = tune(&[], &Weights::default(), &config, |_, _, _| Ok(()));
+    assert!(match tuned { Err(Error::Usage(_)) => true, _ => false });
+}